@@ -1,10 +1,15 @@
 use futures::future::{BoxFuture, FutureExt};
 use futures::task::{waker_ref, ArcWake};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::fmt;
 use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
 use std::pin::Pin;
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender}; // <1>
-use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
 
 struct Hello { // <1>
     state: StateHello,
@@ -49,77 +54,354 @@ impl Future for Hello {
     }
 }
 
+// 型を消去したタスクの出力
+type Output = Box<dyn Any + Send>;
+
 struct Task {
-    // 実行するコルーチン
-    future: Mutex<BoxFuture<'static, ()>>, // <1>
-    // Executorへスケジューリングするためのチャネル
-    sender: SyncSender<Arc<Task>>, // <2>
+    // 実行するコルーチン。完了後はNone
+    future: Mutex<Option<BoxFuture<'static, Output>>>, // <1>
+    // 完了状態
+    join: Mutex<JoinState>,
+    // abortされた場合true
+    aborted: AtomicBool,
+    // Executorへスケジューリングするための実行キュー
+    queue: Arc<RunQueue>, // <2>
+}
+
+// タスクの完了状態
+#[derive(Default)]
+struct JoinState {
+    done: bool,                                // 完了した場合true
+    result: Option<Result<Output, JoinError>>, // JoinHandleが受け取る結果
+    waker: Option<Waker>,                      // 完了を待つJoinHandleのwaker
+}
+
+// JoinHandleが返すエラー
+#[derive(Debug)]
+enum JoinError {
+    Cancelled,        // abortにより中断された
+    Panicked(Output), // パニックした。パニック時の値を保持
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(payload) => {
+                // panic!に渡されたメッセージを取り出す
+                if let Some(msg) = payload.downcast_ref::<&str>() {
+                    write!(f, "task panicked: {}", msg)
+                } else if let Some(msg) = payload.downcast_ref::<String>() {
+                    write!(f, "task panicked: {}", msg)
+                } else {
+                    write!(f, "task panicked")
+                }
+            }
+        }
+    }
+}
+
+impl Task {
+    // 結果を記録し、JoinHandleを起床
+    fn complete(&self, result: Result<Output, JoinError>) {
+        let waker = {
+            let mut join = self.join.lock().unwrap();
+            join.done = true;
+            join.result = Some(result);
+            join.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        self.queue.task_done();
+    }
+}
+
+// Futureを破棄
+// 破棄中にパニックしても、呼び出し元のワーカやepollのスレッドには伝搬させない
+fn drop_future(future: Option<BoxFuture<'static, Output>>) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(future)));
+}
+
+// 完了せずに破棄された場合も、未完了のタスク数から除く
+// 起床するためのwakerがすべて破棄されたタスクは、今後実行されることがない
+impl Drop for Task {
+    fn drop(&mut self) {
+        let future = self.future.get_mut().unwrap().take();
+        if future.is_some() {
+            drop_future(future);
+            self.queue.task_done();
+        }
+    }
 }
 
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) { // <3>
         // 自身をスケジューリング
-        let self0 = arc_self.clone();
-        arc_self.sender.send(self0).unwrap();
+        arc_self.queue.push(arc_self.clone());
+    }
+}
+
+// 複数のワーカスレッドで共有する実行キュー
+// 上限がないため、エンキューでブロックすることはない
+struct RunQueue {
+    state: Mutex<QueueState>,
+    cond: Condvar,
+}
+
+struct QueueState {
+    tasks: VecDeque<Arc<Task>>, // 実行可能なタスク
+    alive: usize,               // 未完了のタスク数
+    spawners: usize,            // Spawnerの数
+}
+
+impl QueueState {
+    // 未完了のタスクがなく、新たにタスクを生成できるSpawnerもない場合は終了
+    fn finished(&self) -> bool {
+        self.alive == 0 && self.spawners == 0
     }
 }
 
+impl RunQueue {
+    fn push(&self, task: Arc<Task>) {
+        self.state.lock().unwrap().tasks.push_back(task);
+        self.cond.notify_one();
+    }
+
+    // 実行可能なタスクがない場合は待機
+    // 終了条件を満たした場合はNoneをリターン
+    fn pop(&self) -> Option<Arc<Task>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.tasks.pop_front() {
+                return Some(task);
+            }
+            if state.finished() {
+                return None;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    // 終了条件を満たした場合は、待機中のワーカをすべて起床
+    fn update(&self, f: impl FnOnce(&mut QueueState)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        if state.finished() {
+            self.cond.notify_all();
+        }
+    }
+
+    fn task_done(&self) {
+        self.update(|s| s.alive -= 1);
+    }
+}
+
+// タスクがパニックした際に呼び出す関数
+type PanicHook = Box<dyn Fn(&JoinError) + Send + Sync>;
+
 struct Executor { // <1>
     // 実行キュー
-    sender: SyncSender<Arc<Task>>,
-    receiver: Receiver<Arc<Task>>,
+    queue: Arc<RunQueue>,
+    workers: usize,                // ワーカスレッドの数
+    panic_hook: Option<PanicHook>, // パニックフック
 }
 
 impl Executor {
+    // CPUの数だけワーカスレッドを利用
     fn new() -> Self {
-        // チャネルを生成。キューのサイズは最大1024個
-        let (sender, receiver) = sync_channel(1024);
+        let n = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_workers(n)
+    }
+
+    fn with_workers(workers: usize) -> Self {
+        assert!(workers > 0);
         Executor {
-            sender: sender.clone(),
-            receiver,
+            queue: Arc::new(RunQueue {
+                state: Mutex::new(QueueState {
+                    tasks: VecDeque::new(),
+                    alive: 0,
+                    spawners: 0,
+                }),
+                cond: Condvar::new(),
+            }),
+            workers,
+            panic_hook: None,
         }
     }
 
+    // タスクがパニックした際に呼び出す関数を設定
+    // JoinHandleで結果を受け取らないタスクのパニックも検知できる
+    fn panic_hook(mut self, hook: impl Fn(&JoinError) + Send + Sync + 'static) -> Self {
+        self.panic_hook = Some(Box::new(hook));
+        self
+    }
+
     // 新たにTaskを生成するためのSpawnerを作成 <2>
     fn get_spawner(&self) -> Spawner {
+        self.queue.update(|s| s.spawners += 1);
         Spawner {
-            sender: self.sender.clone(),
+            queue: self.queue.clone(),
         }
     }
 
+    // ワーカスレッドを起動して実行
+    // すべてのタスクが完了し、すべてのSpawnerが破棄されるとリターン
     fn run(&self) { // <3>
-        // チャネルからTaskを受信して順に実行
-        while let Ok(task) = self.receiver.recv() {
-            // コンテキストを生成
+        std::thread::scope(|s| {
+            for _ in 0..self.workers {
+                s.spawn(|| self.work());
+            }
+        });
+    }
+
+    fn work(&self) {
+        // 実行キューからTaskを取り出して順に実行
+        while let Some(task) = self.queue.pop() {
             let mut future = task.future.lock().unwrap();
-            let waker = waker_ref(&task);
-            let mut ctx = Context::from_waker(&waker);
-            // pollを呼び出し実行
-            let _ = future.as_mut().poll(&mut ctx);
+            // 完了後に古いwakerで起床された場合は何もしない
+            let Some(fut) = future.as_mut() else {
+                continue;
+            };
+
+            let result = if task.aborted.load(Ordering::Acquire) {
+                // abortされた場合は、pollせずに破棄
+                Err(JoinError::Cancelled)
+            } else {
+                // コンテキストを生成
+                let waker = waker_ref(&task);
+                let mut ctx = Context::from_waker(&waker);
+                // pollを呼び出し実行
+                // パニックした場合は、そのタスクのみを終了させ
+                // ワーカは他のタスクの実行を続ける
+                let poll = || fut.as_mut().poll(&mut ctx);
+                match panic::catch_unwind(AssertUnwindSafe(poll)) {
+                    Ok(Poll::Pending) => continue,
+                    Ok(Poll::Ready(output)) => Ok(output),
+                    Err(payload) => {
+                        let err = JoinError::Panicked(payload);
+                        if let Some(hook) = &self.panic_hook {
+                            hook(&err);
+                        }
+                        Err(err)
+                    }
+                }
+            };
+
+            // 完了したFutureはすぐに破棄してリソースを解放
+            drop_future(future.take());
+            drop(future);
+            task.complete(result);
         }
     }
 }
 
 struct Spawner { // <1>
-    sender: SyncSender<Arc<Task>>,
+    queue: Arc<RunQueue>,
 }
 
 impl Spawner {
-    fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) { // <2>
-        let future = future.boxed();    // FutureをBox化
+    fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> { // <2>
+        // 出力をBox化して型を消去し、FutureをBox化
+        let future = future.map(|v| Box::new(v) as Output).boxed();
         let task = Arc::new(Task {      // Task生成
-            future: Mutex::new(future),
-            sender: self.sender.clone(),
+            future: Mutex::new(Some(future)),
+            join: Mutex::new(JoinState::default()),
+            aborted: AtomicBool::new(false),
+            queue: self.queue.clone(),
         });
 
         // 実行キューにエンキュー
-        self.sender.send(task).unwrap();
+        self.queue.update(|s| s.alive += 1);
+        self.queue.push(task.clone());
+        JoinHandle {
+            task,
+            _output: PhantomData,
+        }
+    }
+}
+
+impl Clone for Spawner {
+    fn clone(&self) -> Self {
+        self.queue.update(|s| s.spawners += 1);
+        Spawner {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for Spawner {
+    fn drop(&mut self) {
+        self.queue.update(|s| s.spawners -= 1);
+    }
+}
+
+// タスクの完了を待ち、出力を受け取るためのFuture
+// 破棄してもタスクは実行を続ける
+struct JoinHandle<T> {
+    task: Arc<Task>,
+    _output: PhantomData<T>,
+}
+
+impl<T> JoinHandle<T> {
+    // タスクを中断。次にpollされる際にFutureを破棄する
+    // 完了済みの場合は何もしない
+    fn abort(&self) {
+        self.task.aborted.store(true, Ordering::Release);
+        // 待機中のタスクもすぐに破棄されるよう起床
+        ArcWake::wake_by_ref(&self.task);
+    }
+}
+
+impl<T: 'static> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut join = self.task.join.lock().unwrap();
+        if let Some(result) = join.result.take() {
+            // 型を復元
+            Poll::Ready(result.map(|v| *v.downcast::<T>().unwrap()))
+        } else if join.done {
+            panic!("JoinHandle polled after completion");
+        } else {
+            join.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
     }
 }
 
 fn main() {
     let executor = Executor::new();
     executor.get_spawner().spawn(Hello::new());
+    executor.run(); // すべてのタスクが完了するとリターン
+
+    // JoinHandleで、生成したタスクの完了を待ち出力を受け取ることもできる
+    let spawner = executor.get_spawner();
+    executor.get_spawner().spawn(async move {
+        let h = spawner.spawn(async {
+            Hello::new().await;
+            42
+        });
+        println!("joined: {:?}", h.await);
+
+        // 完了しないタスクを中断
+        let h = spawner.spawn(futures::future::pending::<()>());
+        h.abort();
+        println!("aborted: {:?}", h.await);
+    });
+    executor.run();
+
+    // パニックしたタスクのみが終了し、他のタスクは実行を続ける
+    let executor = Executor::new().panic_hook(|err| println!("panic hook: {}", err));
+    let spawner = executor.get_spawner();
+    executor.get_spawner().spawn(async move {
+        let h: JoinHandle<()> = spawner.spawn(async { panic!("boom") });
+        println!("panicked: {}", h.await.unwrap_err());
+        spawner.spawn(Hello::new()).await.unwrap();
+    });
     executor.run();
 }
 
@@ -132,4 +414,133 @@ fn main() {
 //         h.await; // pollを呼び出し実行
 //     });
 //     executor.run();
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    // 一度だけPendingを返し、実行キューの最後に戻るFuture
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    // 破棄された際にフラグを立てる
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn abort_cancels_and_drops_future() {
+        let executor = Executor::with_workers(2);
+        let spawner = executor.get_spawner();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let flag = DropFlag(dropped.clone());
+        let c = cancelled.clone();
+        let d = dropped.clone();
+        executor.get_spawner().spawn(async move {
+            let h = spawner.spawn(async move {
+                let _flag = flag;
+                futures::future::pending::<()>().await;
+            });
+
+            // 一度pollされて待機中になってから中断
+            YieldNow(false).await;
+            h.abort();
+            let result = h.await;
+
+            // 結果を受け取った時点で、Futureはすでに破棄されている
+            assert!(d.load(Ordering::SeqCst));
+            let is_cancelled = matches!(result, Err(JoinError::Cancelled));
+            c.store(is_cancelled, Ordering::SeqCst);
+        });
+        executor.run();
+        assert!(cancelled.load(Ordering::SeqCst));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn panic_is_isolated_to_task() {
+        // ワーカが1つでも、パニックしたタスク以外は実行を続ける
+        let hooked = Arc::new(AtomicUsize::new(0));
+        let h = hooked.clone();
+        let executor = Executor::with_workers(1).panic_hook(move |err| {
+            assert!(matches!(err, JoinError::Panicked(_)));
+            h.fetch_add(1, Ordering::SeqCst);
+        });
+        let spawner = executor.get_spawner();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let d = done.clone();
+        executor.get_spawner().spawn(async move {
+            let bad: JoinHandle<()> = spawner.spawn(async {
+                YieldNow(false).await;
+                panic!("boom");
+            });
+            let good = spawner.spawn(async {
+                YieldNow(false).await;
+                42
+            });
+
+            let err = bad.await.unwrap_err();
+            assert!(matches!(err, JoinError::Panicked(_)));
+            assert_eq!(err.to_string(), "task panicked: boom");
+            assert_eq!(good.await.unwrap(), 42);
+
+            // パニック後に生成したタスクも実行される
+            spawner.spawn(async { 1 }).await.unwrap();
+            d.fetch_add(1, Ordering::SeqCst);
+        });
+        executor.run();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        assert_eq!(hooked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn run_returns_after_spawners_and_tasks_finish() {
+        let executor = Executor::with_workers(4);
+        let spawner = executor.get_spawner();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        // 他のスレッドがSpawnerを保持している間は、タスクがなくてもrunはリターンしない
+        let d = done.clone();
+        let producer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            for _ in 0..100 {
+                let d = d.clone();
+                let s = spawner.clone();
+                spawner.spawn(async move {
+                    // タスクの中からもタスクを生成
+                    s.spawn(async move {
+                        YieldNow(false).await;
+                        d.fetch_add(1, Ordering::SeqCst);
+                    });
+                    YieldNow(false).await;
+                });
+            }
+        });
+
+        executor.run();
+        assert_eq!(done.load(Ordering::SeqCst), 100);
+        producer.join().unwrap();
+    }
+}
@@ -10,21 +10,28 @@ use nix::{
             EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
         },
         eventfd::{eventfd, EfdFlags}, // eventfd用のインポート <1>
+        socket::{getsockopt, sockopt::SocketError},
     },
     unistd::{read, write},
 };
 use std::{
-    collections::{HashMap, VecDeque},
+    any::Any,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    fmt,
     future::Future,
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{self, BufRead, BufReader, Write},
+    marker::PhantomData,
     net::{SocketAddr, TcpListener, TcpStream},
     os::unix::io::{AsRawFd, RawFd},
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
     sync::{
-        mpsc::{sync_channel, Receiver, SyncSender},
-        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
     },
     task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
 
 fn write_eventfd(fd: RawFd, n: usize) {
@@ -35,7 +42,7 @@ fn write_eventfd(fd: RawFd, n: usize) {
             ptr, std::mem::size_of_val(&n))
     };
     // writeシステムコール呼び出し
-    write(fd, &val).unwrap();
+    write(fd, val).unwrap();
 }
 
 enum IOOps {
@@ -43,9 +50,19 @@ enum IOOps {
     REMOVE(RawFd),                 // epollから削除
 }
 
+// タイマの管理
+#[derive(Default)]
+struct Timers {
+    heap: BinaryHeap<Reverse<(Instant, u64)>>, // 期限とタイマIDの最小ヒープ
+    wakers: HashMap<u64, Waker>,               // タイマIDからwaker
+    id: u64,                                   // 次に割り当てるタイマID
+}
+
 struct IOSelector {
     wakers: Mutex<HashMap<RawFd, Waker>>, // fdからwaker
     queue: Mutex<VecDeque<IOOps>>,        // IOのキュー
+    errors: Mutex<HashMap<RawFd, EpollFlags>>, // fdで発生したエラー
+    timers: Mutex<Timers>, // タイマ
     epfd: RawFd,  // epollのfd
     event: RawFd, // eventfdのfd
 }
@@ -55,6 +72,8 @@ impl IOSelector {
         let s = IOSelector {
             wakers: Mutex::new(HashMap::new()),
             queue: Mutex::new(VecDeque::new()),
+            errors: Mutex::new(HashMap::new()),
+            timers: Mutex::new(Timers::default()),
             epfd: epoll_create1(EpollCreateFlags::empty()).unwrap(),
             // eventfd生成
             event: eventfd(0, EfdFlags::empty()).unwrap(), // <2>
@@ -101,7 +120,7 @@ impl IOSelector {
             }
         }
 
-        assert!(!wakers.contains_key(&fd));
+        // タイムアウトなどで再度pollされ、登録済みの場合はwakerを置き換える
         wakers.insert(fd, waker); // <7>
     }
 
@@ -112,6 +131,21 @@ impl IOSelector {
                                      fd as u64);
         epoll_ctl(self.epfd, epoll_del, fd, &mut ev).ok();
         wakers.remove(&fd);
+        self.errors.lock().unwrap().remove(&fd);
+    }
+
+    // EPOLLERRとEPOLLHUPをio::Errorに変換
+    // EPOLLERRの場合はソケットのエラーを取得する
+    fn to_error(fd: RawFd, flags: EpollFlags) -> io::Error {
+        if flags.contains(EpollFlags::EPOLLERR) {
+            match getsockopt(fd, SocketError) {
+                Ok(errno) if errno != 0 =>
+                    io::Error::from_raw_os_error(errno),
+                _ => io::Error::other("epoll: error"),
+            }
+        } else {
+            io::Error::new(io::ErrorKind::BrokenPipe, "epoll: hang up")
+        }
     }
 
     fn select(&self) { // <9>
@@ -127,11 +161,13 @@ impl IOSelector {
 
         let mut events = vec![EpollEvent::empty(); 1024];
         // event発生を監視
+        // 最も近いタイマの期限までevent発生を監視
         while let Ok(nfds) = epoll_wait(self.epfd, // <11>
-                                        &mut events, -1) {
+                                        &mut events,
+                                        self.next_timeout()) {
             let mut t = self.wakers.lock().unwrap();
-            for n in 0..nfds {
-                if events[n].data() == self.event as u64 {
+            for ev in events.iter().take(nfds) {
+                if ev.data() == self.event as u64 {
                     // eventfdの場合、追加、削除要求を処理 <12>
                     let mut q = self.queue.lock().unwrap();
                     while let Some(op) = q.pop_front() {
@@ -146,28 +182,142 @@ impl IOSelector {
                     let mut buf: [u8; 8] = [0; 8];
                     read(self.event, &mut buf).unwrap(); // eventfdの通知解除
                 } else {
+                    let data = ev.data() as i32;
+                    // エラーとハングアップを記録
+                    // 通常は再度pollされた際の読み書きでエラーとなるが、
+                    // それでも読み書きできない場合は登録時にエラーを返す
+                    // 削除済みのfdは再利用されている可能性があるため記録しない
+                    let err = ev.events()
+                        & (EpollFlags::EPOLLERR | EpollFlags::EPOLLHUP);
                     // 実行キューに追加 <13>
-                    let data = events[n].data() as i32;
-                    let waker = t.remove(&data).unwrap();
-                    waker.wake_by_ref();
+                    // 削除済みのfdの場合はwakerが存在しない
+                    if let Some(waker) = t.remove(&data) {
+                        if !err.is_empty() {
+                            self.errors.lock().unwrap().insert(data, err);
+                        }
+                        waker.wake_by_ref();
+                    }
+                }
+            }
+            drop(t);
+
+            // 期限切れのタイマを起床
+            self.fire_timers();
+        }
+    }
+
+    // 最も近いタイマの期限までのミリ秒を返す
+    // タイマがない場合は-1を返し、無期限に待機させる
+    fn next_timeout(&self) -> isize {
+        let mut timers = self.timers.lock().unwrap();
+        while let Some(&Reverse((deadline, id))) = timers.heap.peek() {
+            if !timers.wakers.contains_key(&id) {
+                // 削除済みのタイマ
+                timers.heap.pop();
+                continue;
+            }
+            // 期限より早く起床しないよう切り上げる
+            // epoll_waitのタイムアウトはc_intのため、i32::MAXで打ち切る
+            let d = deadline.saturating_duration_since(Instant::now());
+            return d.as_nanos().div_ceil(1_000_000)
+                .min(i32::MAX as u128) as isize;
+        }
+        -1
+    }
+
+    fn fire_timers(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        {
+            let mut timers = self.timers.lock().unwrap();
+            while let Some(&Reverse((deadline, id))) = timers.heap.peek() {
+                if deadline > now {
+                    break;
+                }
+                timers.heap.pop();
+                if let Some(waker) = timers.wakers.remove(&id) {
+                    expired.push(waker);
                 }
             }
         }
+        // ロックを解放してから起床
+        for waker in expired {
+            waker.wake();
+        }
     }
 
     // ファイルディスクリプタ登録用関数 <14>
-    fn register(&self, flags: EpollFlags, fd: RawFd, waker: Waker) {
+    // 前回の監視でエラーかハングアップが発生していた場合は、登録せずにエラーを返す
+    fn register(&self, flags: EpollFlags, fd: RawFd,
+                waker: Waker) -> io::Result<()> {
+        if let Some(err) = self.errors.lock().unwrap().remove(&fd) {
+            return Err(Self::to_error(fd, err));
+        }
         let mut q = self.queue.lock().unwrap();
         q.push_back(IOOps::ADD(flags, fd, waker));
         write_eventfd(self.event, 1);
+        Ok(())
     }
 
     // ファイルディスクリプタ削除用関数 <15>
+    // fdは呼び出し後すぐに閉じられ、再利用される可能性があるため、
+    // wakerとエラーの記録はepollのスレッドを待たずに削除する
     fn unregister(&self, fd: RawFd) {
+        // wakerの破棄でタスクが解放される場合に備え、ロックを解放してから破棄
+        let waker = self.wakers.lock().unwrap().remove(&fd);
+        drop(waker);
+        self.errors.lock().unwrap().remove(&fd);
+
         let mut q = self.queue.lock().unwrap();
         q.push_back(IOOps::REMOVE(fd));
         write_eventfd(self.event, 1);
     }
+
+    // タイマ登録用関数。タイマIDをリターン
+    fn add_timer(&self, deadline: Instant, waker: Waker) -> u64 {
+        let mut timers = self.timers.lock().unwrap();
+        let id = timers.id;
+        timers.id += 1;
+
+        let earliest = timers.heap.peek()
+            .is_none_or(|&Reverse((d, _))| deadline < d);
+        timers.heap.push(Reverse((deadline, id)));
+        timers.wakers.insert(id, waker);
+
+        // 最も近い期限が更新された場合は、
+        // eventfdで通知してepoll_waitのタイムアウトを再計算させる
+        if earliest {
+            write_eventfd(self.event, 1);
+        }
+        id
+    }
+
+    // タイマのwaker更新用関数
+    // 発火済みなどで登録されていない場合はfalseをリターン
+    fn update_timer(&self, id: u64, waker: &Waker) -> bool {
+        let mut timers = self.timers.lock().unwrap();
+        match timers.wakers.get_mut(&id) {
+            Some(w) => {
+                if !w.will_wake(waker) {
+                    *w = waker.clone();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // タイマ削除用関数
+    // ヒープからは、先頭に来た際にnext_timeoutで削除される
+    // 削除済みのタイマがヒープの大半を占める場合は、まとめて削除する
+    fn remove_timer(&self, id: u64) {
+        let mut timers = self.timers.lock().unwrap();
+        timers.wakers.remove(&id);
+        if timers.heap.len() > 2 * timers.wakers.len() + 64 {
+            let Timers { heap, wakers, .. } = &mut *timers;
+            heap.retain(|Reverse((_, id))| wakers.contains_key(id));
+        }
+    }
 }
 
 struct AsyncListener { // <1>
@@ -185,13 +335,13 @@ impl AsyncListener {
         listener.set_nonblocking(true).unwrap();
 
         AsyncListener {
-            listener: listener,
-            selector: selector,
+            listener,
+            selector,
         }
     }
 
     // コネクションをアクセプトするためのFutureをリターン <3>
-    fn accept(&self) -> Accept {
+    fn accept(&self) -> Accept<'_> {
         Accept { listener: self }
     }
 }
@@ -208,9 +358,9 @@ struct Accept<'a> {
 
 impl<'a> Future for Accept<'a> {
     // 返り値の型
-    type Output = (AsyncReader,          // 非同期読み込みストリーム
-                   BufWriter<TcpStream>, // 書き込みストリーム
-                   SocketAddr);          // アドレス
+    type Output = io::Result<(AsyncReader, // 非同期読み込みストリーム
+                              AsyncWriter, // 非同期書き込みストリーム
+                              SocketAddr)>; // アドレス
 
     fn poll(self: Pin<&mut Self>,
             cx: &mut Context<'_>) -> Poll<Self::Output> {
@@ -219,12 +369,13 @@ impl<'a> Future for Accept<'a> {
             Ok((stream, addr)) => {
                 // アクセプトした場合は
                 // 読み込みと書き込み用オブジェクトおよびアドレスをリターン <2>
-                let stream0 = stream.try_clone().unwrap();
-                Poll::Ready((
-                    AsyncReader::new(stream0, self.listener.selector.clone()),
-                    BufWriter::new(stream),
+                let stream0 = stream.try_clone()?;
+                let selector = &self.listener.selector;
+                Poll::Ready(Ok((
+                    AsyncReader::new(stream0, selector.clone()),
+                    AsyncWriter::new(stream, selector.clone()),
                     addr,
-                ))
+                )))
             }
             Err(err) => {
                 // アクセプトすべきコネクションがない場合はepollに登録 <3>
@@ -233,10 +384,10 @@ impl<'a> Future for Accept<'a> {
                         EpollFlags::EPOLLIN,
                         self.listener.listener.as_raw_fd(),
                         cx.waker().clone(),
-                    );
+                    )?;
                     Poll::Pending
                 } else {
-                    panic!("accept: {}", err);
+                    Poll::Ready(Err(err))
                 }
             }
         }
@@ -257,12 +408,12 @@ impl AsyncReader {
         AsyncReader {
             fd: stream.as_raw_fd(),
             reader: BufReader::new(stream),
-            selector: selector,
+            selector,
         }
     }
 
     // 1行読み込みのためのFutureをリターン
-    fn read_line(&mut self) -> ReadLine {
+    fn read_line(&mut self) -> ReadLine<'_> {
         ReadLine { reader: self }
     }
 }
@@ -278,16 +429,16 @@ struct ReadLine<'a> {
 }
 
 impl<'a> Future for ReadLine<'a> {
-    // 返り値の型
-    type Output = Option<String>;
+    // 返り値の型。コネクションクローズの場合はNone
+    type Output = io::Result<Option<String>>;
 
     fn poll(mut self: Pin<&mut Self>,
             cx: &mut Context<'_>) -> Poll<Self::Output> {
         let mut line = String::new();
         // 非同期読み込み
         match self.reader.reader.read_line(&mut line) { // <1>
-            Ok(0) => Poll::Ready(None),  // コネクションクローズ
-            Ok(_) => Poll::Ready(Some(line)), // 1行読み込み成功
+            Ok(0) => Poll::Ready(Ok(None)),  // コネクションクローズ
+            Ok(_) => Poll::Ready(Ok(Some(line))), // 1行読み込み成功
             Err(err) => {
                 // 読み込みできない場合はepollに登録 <2>
                 if err.kind() == std::io::ErrorKind::WouldBlock {
@@ -295,86 +446,531 @@ impl<'a> Future for ReadLine<'a> {
                         EpollFlags::EPOLLIN,
                         self.reader.fd,
                         cx.waker().clone(),
-                    );
+                    )?;
                     Poll::Pending
                 } else {
-                    Poll::Ready(None)
+                    Poll::Ready(Err(err))
                 }
             }
         }
     }
 }
 
+// 書き込みバッファのサイズ
+const BUF_SIZE: usize = 8 * 1024;
+
+// 非同期書き込みストリーム
+// BufWriterと同じくバッファリングするため、最後にflushを呼び出すこと
+struct AsyncWriter {
+    fd: RawFd,
+    stream: TcpStream,
+    buf: Vec<u8>, // 書き込みバッファ
+    selector: Arc<IOSelector>,
+}
+
+impl AsyncWriter {
+    fn new(stream: TcpStream,
+           selector: Arc<IOSelector>) -> AsyncWriter {
+        // ノンブロッキングに設定
+        stream.set_nonblocking(true).unwrap();
+        AsyncWriter {
+            fd: stream.as_raw_fd(),
+            stream,
+            buf: Vec::with_capacity(BUF_SIZE),
+            selector,
+        }
+    }
+
+    // データをすべて書き込むためのFutureをリターン
+    fn write_all<'a>(&'a mut self, data: &'a [u8]) -> WriteAll<'a> {
+        WriteAll { writer: self, data: Some(data) }
+    }
+
+    // バッファの内容をすべて書き込むためのFutureをリターン
+    fn flush(&mut self) -> Flush<'_> {
+        Flush { writer: self }
+    }
+
+    // バッファが空になるまで書き込み
+    fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buf.is_empty() {
+            match self.stream.write(&self.buf) {
+                Ok(0) => {
+                    return Poll::Ready(Err(
+                        io::ErrorKind::WriteZero.into()));
+                }
+                Ok(n) => {
+                    self.buf.drain(..n);
+                }
+                Err(err) => {
+                    // 書き込みできない場合はepollに登録
+                    if err.kind() == io::ErrorKind::WouldBlock {
+                        self.selector.register(
+                            EpollFlags::EPOLLOUT,
+                            self.fd,
+                            cx.waker().clone(),
+                        )?;
+                        return Poll::Pending;
+                    } else if err.kind() != io::ErrorKind::Interrupted {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for AsyncWriter {
+    fn drop(&mut self) {
+        self.selector.unregister(self.fd);
+    }
+}
+
+struct WriteAll<'a> {
+    writer: &'a mut AsyncWriter,
+    data: Option<&'a [u8]>, // バッファへ未追加のデータ
+}
+
+impl<'a> Future for WriteAll<'a> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // バッファに追加し、バッファが一杯になった場合のみ書き込む
+        if let Some(data) = self.data.take() {
+            self.writer.buf.extend_from_slice(data);
+        }
+        if self.writer.buf.len() < BUF_SIZE {
+            return Poll::Ready(Ok(()));
+        }
+        self.writer.poll_flush(cx)
+    }
+}
+
+struct Flush<'a> {
+    writer: &'a mut AsyncWriter,
+}
+
+impl<'a> Future for Flush<'a> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.writer.poll_flush(cx)
+    }
+}
+
+// 指定時間後に完了するFutureをリターン
+// Instantで表せないほど長い時間の場合は完了しない
+fn sleep(dur: Duration, selector: Arc<IOSelector>) -> Sleep {
+    Sleep {
+        deadline: Instant::now().checked_add(dur),
+        id: None,
+        selector,
+    }
+}
+
+struct Sleep {
+    deadline: Option<Instant>, // Noneなら完了しない
+    id: Option<u64>, // 登録中のタイマID
+    selector: Arc<IOSelector>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(deadline) = self.deadline else {
+            return Poll::Pending; // 期限なし
+        };
+        if Instant::now() >= deadline {
+            if let Some(id) = self.id.take() {
+                self.selector.remove_timer(id);
+            }
+            return Poll::Ready(());
+        }
+
+        // 期限は一度だけ登録し、再度pollされた場合はwakerのみ更新する
+        if let Some(id) = self.id {
+            if self.selector.update_timer(id, cx.waker()) {
+                return Poll::Pending;
+            }
+        }
+        let id = self.selector.add_timer(deadline, cx.waker().clone());
+        self.id = Some(id);
+        Poll::Pending
+    }
+}
+
+// 完了前に破棄された場合はタイマを削除
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.selector.remove_timer(id);
+        }
+    }
+}
+
+// 指定時間内に完了しない場合、TimedOutエラーとなるFutureをリターン
+fn timeout<F: Future>(future: F, dur: Duration,
+                      selector: Arc<IOSelector>) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(dur, selector),
+    }
+}
+
+struct Timeout<F> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = io::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut, "timed out"))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// 以下のExecutor、Spawner、JoinHandleと関連する型は、
+// 5.2のch5_2_2_schedと同じ実装のコピー。変更する場合は両方を合わせて修正する
+// テストも5.2のものを参照
+
+// 型を消去したタスクの出力
+type Output = Box<dyn Any + Send>;
+
 struct Task {
-    // 実行するコルーチン
-    future: Mutex<BoxFuture<'static, ()>>, // <1>
-    // Executorへスケジューリングするためのチャネル
-    sender: SyncSender<Arc<Task>>, // <2>
+    // 実行するコルーチン。完了後はNone
+    future: Mutex<Option<BoxFuture<'static, Output>>>, // <1>
+    // 完了状態
+    join: Mutex<JoinState>,
+    // abortされた場合true
+    aborted: AtomicBool,
+    // Executorへスケジューリングするための実行キュー
+    queue: Arc<RunQueue>, // <2>
+}
+
+// タスクの完了状態
+#[derive(Default)]
+struct JoinState {
+    done: bool,                                // 完了した場合true
+    result: Option<Result<Output, JoinError>>, // JoinHandleが受け取る結果
+    waker: Option<Waker>,                      // 完了を待つJoinHandleのwaker
+}
+
+// JoinHandleが返すエラー
+#[derive(Debug)]
+enum JoinError {
+    Cancelled,        // abortにより中断された
+    Panicked(Output), // パニックした。パニック時の値を保持
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(payload) => {
+                // panic!に渡されたメッセージを取り出す
+                if let Some(msg) = payload.downcast_ref::<&str>() {
+                    write!(f, "task panicked: {}", msg)
+                } else if let Some(msg) = payload.downcast_ref::<String>() {
+                    write!(f, "task panicked: {}", msg)
+                } else {
+                    write!(f, "task panicked")
+                }
+            }
+        }
+    }
+}
+
+impl Task {
+    // 結果を記録し、JoinHandleを起床
+    fn complete(&self, result: Result<Output, JoinError>) {
+        let waker = {
+            let mut join = self.join.lock().unwrap();
+            join.done = true;
+            join.result = Some(result);
+            join.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        self.queue.task_done();
+    }
+}
+
+// Futureを破棄
+// 破棄中にパニックしても、呼び出し元のワーカやepollのスレッドには伝搬させない
+fn drop_future(future: Option<BoxFuture<'static, Output>>) {
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(future)));
+}
+
+// 完了せずに破棄された場合も、未完了のタスク数から除く
+// 起床するためのwakerがすべて破棄されたタスクは、今後実行されることがない
+impl Drop for Task {
+    fn drop(&mut self) {
+        let future = self.future.get_mut().unwrap().take();
+        if future.is_some() {
+            drop_future(future);
+            self.queue.task_done();
+        }
+    }
 }
 
 impl ArcWake for Task {
     fn wake_by_ref(arc_self: &Arc<Self>) { // <3>
         // 自身をスケジューリング
-        let self0 = arc_self.clone();
-        arc_self.sender.send(self0).unwrap();
+        arc_self.queue.push(arc_self.clone());
+    }
+}
+
+// 複数のワーカスレッドで共有する実行キュー
+// 上限がないため、エンキューでブロックすることはない
+struct RunQueue {
+    state: Mutex<QueueState>,
+    cond: Condvar,
+}
+
+struct QueueState {
+    tasks: VecDeque<Arc<Task>>, // 実行可能なタスク
+    alive: usize,               // 未完了のタスク数
+    spawners: usize,            // Spawnerの数
+}
+
+impl QueueState {
+    // 未完了のタスクがなく、新たにタスクを生成できるSpawnerもない場合は終了
+    fn finished(&self) -> bool {
+        self.alive == 0 && self.spawners == 0
+    }
+}
+
+impl RunQueue {
+    fn push(&self, task: Arc<Task>) {
+        self.state.lock().unwrap().tasks.push_back(task);
+        self.cond.notify_one();
+    }
+
+    // 実行可能なタスクがない場合は待機
+    // 終了条件を満たした場合はNoneをリターン
+    fn pop(&self) -> Option<Arc<Task>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(task) = state.tasks.pop_front() {
+                return Some(task);
+            }
+            if state.finished() {
+                return None;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
+
+    // 終了条件を満たした場合は、待機中のワーカをすべて起床
+    fn update(&self, f: impl FnOnce(&mut QueueState)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        if state.finished() {
+            self.cond.notify_all();
+        }
+    }
+
+    fn task_done(&self) {
+        self.update(|s| s.alive -= 1);
     }
 }
 
+// タスクがパニックした際に呼び出す関数
+type PanicHook = Box<dyn Fn(&JoinError) + Send + Sync>;
+
 struct Executor { // <1>
     // 実行キュー
-    sender: SyncSender<Arc<Task>>,
-    receiver: Receiver<Arc<Task>>,
+    queue: Arc<RunQueue>,
+    workers: usize,                // ワーカスレッドの数
+    panic_hook: Option<PanicHook>, // パニックフック
 }
 
 impl Executor {
+    // CPUの数だけワーカスレッドを利用
     fn new() -> Self {
-        // チャネルを生成。キューのサイズは最大1024個
-        let (sender, receiver) = sync_channel(1024);
+        let n = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_workers(n)
+    }
+
+    fn with_workers(workers: usize) -> Self {
+        assert!(workers > 0);
         Executor {
-            sender: sender.clone(),
-            receiver,
+            queue: Arc::new(RunQueue {
+                state: Mutex::new(QueueState {
+                    tasks: VecDeque::new(),
+                    alive: 0,
+                    spawners: 0,
+                }),
+                cond: Condvar::new(),
+            }),
+            workers,
+            panic_hook: None,
         }
     }
 
+    // タスクがパニックした際に呼び出す関数を設定
+    // JoinHandleで結果を受け取らないタスクのパニックも検知できる
+    fn panic_hook(mut self, hook: impl Fn(&JoinError) + Send + Sync + 'static) -> Self {
+        self.panic_hook = Some(Box::new(hook));
+        self
+    }
+
     // 新たにTaskを生成するためのSpawnerを作成 <2>
     fn get_spawner(&self) -> Spawner {
+        self.queue.update(|s| s.spawners += 1);
         Spawner {
-            sender: self.sender.clone(),
+            queue: self.queue.clone(),
         }
     }
 
+    // ワーカスレッドを起動して実行
+    // すべてのタスクが完了し、すべてのSpawnerが破棄されるとリターン
     fn run(&self) { // <3>
-        // チャネルからTaskを受信して順に実行
-        while let Ok(task) = self.receiver.recv() {
-            // コンテキストを生成
+        std::thread::scope(|s| {
+            for _ in 0..self.workers {
+                s.spawn(|| self.work());
+            }
+        });
+    }
+
+    fn work(&self) {
+        // 実行キューからTaskを取り出して順に実行
+        while let Some(task) = self.queue.pop() {
             let mut future = task.future.lock().unwrap();
-            let waker = waker_ref(&task);
-            let mut ctx = Context::from_waker(&waker);
-            // pollを呼び出し実行
-            let _ = future.as_mut().poll(&mut ctx);
+            // 完了後に古いwakerで起床された場合は何もしない
+            let Some(fut) = future.as_mut() else {
+                continue;
+            };
+
+            let result = if task.aborted.load(Ordering::Acquire) {
+                // abortされた場合は、pollせずに破棄
+                Err(JoinError::Cancelled)
+            } else {
+                // コンテキストを生成
+                let waker = waker_ref(&task);
+                let mut ctx = Context::from_waker(&waker);
+                // pollを呼び出し実行
+                // パニックした場合は、そのタスクのみを終了させ
+                // ワーカは他のタスクの実行を続ける
+                let poll = || fut.as_mut().poll(&mut ctx);
+                match panic::catch_unwind(AssertUnwindSafe(poll)) {
+                    Ok(Poll::Pending) => continue,
+                    Ok(Poll::Ready(output)) => Ok(output),
+                    Err(payload) => {
+                        let err = JoinError::Panicked(payload);
+                        if let Some(hook) = &self.panic_hook {
+                            hook(&err);
+                        }
+                        Err(err)
+                    }
+                }
+            };
+
+            // 完了したFutureはすぐに破棄してリソースを解放
+            drop_future(future.take());
+            drop(future);
+            task.complete(result);
         }
     }
 }
 
 struct Spawner { // <1>
-    sender: SyncSender<Arc<Task>>,
+    queue: Arc<RunQueue>,
 }
 
 impl Spawner {
-    fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) { // <2>
-        let future = future.boxed();    // FutureをBox化
+    fn spawn<T: Send + 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> JoinHandle<T> { // <2>
+        // 出力をBox化して型を消去し、FutureをBox化
+        let future = future.map(|v| Box::new(v) as Output).boxed();
         let task = Arc::new(Task {      // Task生成
-            future: Mutex::new(future),
-            sender: self.sender.clone(),
+            future: Mutex::new(Some(future)),
+            join: Mutex::new(JoinState::default()),
+            aborted: AtomicBool::new(false),
+            queue: self.queue.clone(),
         });
 
         // 実行キューにエンキュー
-        self.sender.send(task).unwrap();
+        self.queue.update(|s| s.alive += 1);
+        self.queue.push(task.clone());
+        JoinHandle {
+            task,
+            _output: PhantomData,
+        }
+    }
+}
+
+impl Clone for Spawner {
+    fn clone(&self) -> Self {
+        self.queue.update(|s| s.spawners += 1);
+        Spawner {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for Spawner {
+    fn drop(&mut self) {
+        self.queue.update(|s| s.spawners -= 1);
+    }
+}
+
+// タスクの完了を待ち、出力を受け取るためのFuture
+// 破棄してもタスクは実行を続ける
+struct JoinHandle<T> {
+    task: Arc<Task>,
+    _output: PhantomData<T>,
+}
+
+impl<T> JoinHandle<T> {
+    // タスクを中断。次にpollされる際にFutureを破棄する
+    // 完了済みの場合は何もしない
+    #[allow(dead_code)] // エコーサーバでは使用しない
+    fn abort(&self) {
+        self.task.aborted.store(true, Ordering::Release);
+        // 待機中のタスクもすぐに破棄されるよう起床
+        ArcWake::wake_by_ref(&self.task);
+    }
+}
+
+impl<T: 'static> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut join = self.task.join.lock().unwrap();
+        if let Some(result) = join.result.take() {
+            // 型を復元
+            Poll::Ready(result.map(|v| *v.downcast::<T>().unwrap()))
+        } else if join.done {
+            panic!("JoinHandle polled after completion");
+        } else {
+            join.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
     }
 }
 
 fn main() {
-    let executor = Executor::new();
+    // コネクションのタスクがパニックしても、サーバは実行を続ける
+    let executor =
+        Executor::new().panic_hook(|err| println!("error: {}", err));
     let selector = IOSelector::new();
     let spawner = executor.get_spawner();
 
@@ -385,18 +981,28 @@ fn main() {
         loop {
             // 非同期コネクションアクセプト <3>
             let (mut reader, mut writer, addr) =
-                listener.accept().await;
+                listener.accept().await.unwrap();
             println!("accept: {}", addr);
+            let selector = selector.clone();
 
             // コネクションごとにタスクを生成 <4>
             spawner.spawn(async move {
-                // 1行非同期読み込み <5>
-                while let Some(buf) = reader.read_line().await {
-                    print!("read: {}, {}", addr, buf);
-                    writer.write(buf.as_bytes()).unwrap();
-                    writer.flush().unwrap();
+                // 1行非同期読み込みし、非同期に書き戻す <5>
+                let result: io::Result<()> = async {
+                    // 一定時間改行が送られない場合はタイムアウト
+                    while let Some(buf) = timeout(reader.read_line(),
+                                                  Duration::from_secs(10),
+                                                  selector.clone()).await?? {
+                        print!("read: {}, {}", addr, buf);
+                        writer.write_all(buf.as_bytes()).await?;
+                        writer.flush().await?;
+                    }
+                    Ok(())
+                }.await;
+                match result {
+                    Ok(()) => println!("close: {}", addr),
+                    Err(err) => println!("error: {}, {}", addr, err),
                 }
-                println!("close: {}", addr);
             });
         }
     };
@@ -404,4 +1010,55 @@ fn main() {
     // タスクを生成して実行
     executor.get_spawner().spawn(server);
     executor.run();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker;
+
+    #[test]
+    fn sleep_registers_deadline_once() {
+        let selector = IOSelector::new();
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // 何度pollしても、ヒープに登録される期限は1つ
+        let mut s = sleep(Duration::from_secs(60), selector.clone());
+        for _ in 0..10 {
+            assert!(Pin::new(&mut s).poll(&mut cx).is_pending());
+        }
+        assert_eq!(selector.timers.lock().unwrap().heap.len(), 1);
+        drop(s);
+
+        // 削除したタイマは、期限を待たずにヒープからまとめて削除される
+        for _ in 0..1000 {
+            let mut s = sleep(Duration::from_secs(60), selector.clone());
+            assert!(Pin::new(&mut s).poll(&mut cx).is_pending());
+        }
+        let timers = selector.timers.lock().unwrap();
+        assert!(timers.wakers.is_empty());
+        assert!(timers.heap.len() <= 64);
+    }
+
+    #[test]
+    fn run_waits_for_tasks_sleeping_on_timers() {
+        let executor = Executor::with_workers(2);
+        let selector = IOSelector::new();
+        let done = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        // Spawnerを破棄しても、タイマを待つタスクが完了するまでrunはリターンしない
+        let spawner = executor.get_spawner();
+        for i in 0..10 {
+            let selector = selector.clone();
+            let done = done.clone();
+            spawner.spawn(async move {
+                sleep(Duration::from_millis(5 * i), selector).await;
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(spawner);
+        executor.run();
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+    }
+}
@@ -1,17 +1,25 @@
+use std::env;
 use std::process::Command;
 
-const ASM_FILE: &str = "asm/context.S";
 const O_FILE: &str = "asm/context.o";
 const LIB_FILE: &str = "asm/libcontext.a";
 
 fn main() {
-    Command::new("cc").args(&[ASM_FILE, "-c", "-fPIC", "-o"])
-                       .arg(O_FILE)
-                       .status().unwrap();
-    Command::new("ar").args(&["crus", LIB_FILE, O_FILE])
-                      .status().unwrap();
+    // ターゲットのアーキテクチャに応じたアセンブリを選択
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let asm_file = format!("asm/context_{}.S", arch);
 
-    println!("cargo:rustc-link-search=native={}", "asm"); // asmをライブラリ検索パスに追加
-    println!("cargo:rustc-link-lib=static=context");  // libcontext.aという静的ライブラリをリンク
-    println!("cargo:rerun-if-changed=asm/context.S"); // asm/context.Sというファイルに依存
-}
\ No newline at end of file
+    Command::new("cc")
+        .args([&asm_file, "-c", "-fPIC", "-o"])
+        .arg(O_FILE)
+        .status()
+        .unwrap();
+    Command::new("ar")
+        .args(["crus", LIB_FILE, O_FILE])
+        .status()
+        .unwrap();
+
+    println!("cargo:rustc-link-search=native=asm"); // asmをライブラリ検索パスに追加
+    println!("cargo:rustc-link-lib=static=context"); // libcontext.aという静的ライブラリをリンク
+    println!("cargo:rerun-if-changed={}", asm_file); // アセンブリのファイルに依存
+}
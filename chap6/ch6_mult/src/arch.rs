@@ -0,0 +1,25 @@
+// アーキテクチャ依存のコンテキストスイッチ
+// レジスタの保存・復元はasm/context_<アーキテクチャ名>.Sで実装し、
+// 対象のアーキテクチャのものをbuild.rsでリンクする
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::Registers;
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::Registers;
+
+#[cfg(not(any(target_arch = "aarch64", target_arch = "x86_64")))]
+compile_error!("only aarch64 and x86_64 are supported");
+
+extern "C" {
+    // 現在のレジスタをctxに保存して0を返す
+    // switch_contextで復元されると、再度この関数から1を返す
+    pub fn set_context(ctx: *mut Registers) -> u64;
+
+    // ctxに保存されたレジスタを復元する。呼び出し元には戻らない
+    pub fn switch_context(ctx: *const Registers) -> !;
+}
@@ -0,0 +1,27 @@
+#[repr(C)] // <1>
+#[rustfmt::skip]
+pub struct Registers { // <2>
+    // callee保存レジスタ
+     d8: u64,  d9: u64, d10: u64, d11: u64, d12: u64,
+    d13: u64, d14: u64, d15: u64, x19: u64, x20: u64,
+    x21: u64, x22: u64, x23: u64, x24: u64, x25: u64,
+    x26: u64, x27: u64, x28: u64,
+
+    x30: u64, // リンクレジスタ
+    sp: u64,  // スタックポインタ
+}
+
+impl Registers {
+    // spをスタックポインタとし、entryから実行を開始するレジスタを生成
+    #[rustfmt::skip]
+    pub fn new(sp: u64, entry: extern "C" fn()) -> Self { // <3>
+        Registers {
+             d8: 0,  d9: 0, d10: 0, d11: 0, d12: 0,
+            d13: 0, d14: 0, d15: 0, x19: 0, x20: 0,
+            x21: 0, x22: 0, x23: 0, x24: 0, x25: 0,
+            x26: 0, x27: 0, x28: 0,
+            x30: entry as usize as u64, // <4>
+            sp,
+        }
+    }
+}
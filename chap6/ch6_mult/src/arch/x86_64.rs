@@ -0,0 +1,30 @@
+#[repr(C)]
+pub struct Registers {
+    // callee保存レジスタ
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+
+    rsp: u64, // スタックポインタ
+    rdx: u64, // 戻り先アドレス
+}
+
+impl Registers {
+    // rspをスタックポインタとし、entryから実行を開始するレジスタを生成
+    pub fn new(rsp: u64, entry: extern "C" fn()) -> Self {
+        Registers {
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            // call命令で呼び出された直後と同じく、16バイト境界から8バイトずらす
+            rsp: rsp.wrapping_sub(8),
+            rdx: entry as usize as u64,
+        }
+    }
+}
@@ -1,64 +1,47 @@
-use nix::sys::mman::{mprotect, ProtFlags};
-use rand;
+use crate::arch::{set_context, switch_context, Registers};
+use crate::trace::{EventKind, Trace};
+use crate::{overflow, preempt};
+use nix::errno::Errno;
+use nix::sys::epoll::{
+    epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp,
+};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::sys::mman::{mmap, mprotect, munmap, MapFlags, ProtFlags};
+use nix::unistd::{close, read, write};
 use std::alloc::{alloc, dealloc, Layout};
-use std::collections::{HashMap, HashSet, LinkedList};
+use std::any::Any;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, LinkedList, VecDeque};
 use std::ffi::c_void;
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-// すべてのスレッド終了時に戻ってくる先 <1>
-static mut CTX_MAIN: Option<Box<Registers>> = None;
-
-// 不要なスタック領域 <2>
-static mut UNUSED_STACK: (*mut u8, Layout) = (ptr::null_mut(), Layout::new::<u8>());
-
-// スレッドの実行キュー <3>
-static mut CONTEXTS: LinkedList<Box<Context>> = LinkedList::new();
-
-// スレッドIDの集合 <4>
-static mut ID: *mut HashSet<u64> = ptr::null_mut();
-
-// メッセージキュー <1>
-static mut MESSAGES: *mut MappedList<u64> = ptr::null_mut();
-
-// 待機スレッド集合 <2>
-static mut WAITING: *mut HashMap<u64, Box<Context>> = ptr::null_mut();
-
-#[repr(C)] // <1>
-struct Registers { // <2>
-    // callee保存レジスタ
-     d8: u64,  d9: u64, d10: u64, d11: u64, d12: u64,
-    d13: u64, d14: u64, d15: u64, x19: u64, x20: u64,
-    x21: u64, x22: u64, x23: u64, x24: u64, x25: u64,
-    x26: u64, x27: u64, x28: u64,
-
-    x30: u64, // リンクレジスタ
-    sp: u64,  // スタックポインタ
-}
-
-impl Registers {
-    fn new(sp: u64) -> Self { // <3>
-        Registers {
-             d8: 0,  d9: 0, d10: 0, d11: 0, d12: 0,
-            d13: 0, d14: 0, d15: 0, x19: 0, x20: 0,
-            x21: 0, x22: 0, x23: 0, x24: 0, x25: 0,
-            x26: 0, x27: 0, x28: 0,
-            x30: entry_point as u64, // <4>
-            sp,
-        }
-    }
-}
-
-extern "C" {
-    fn set_context(ctx: *mut Registers) -> u64;
-    fn switch_context(ctx: *const Registers) -> !;
+thread_local! {
+    // このOSスレッドで実行中のワーカ
+    // Runtime::runの実行中のみ設定される
+    static CURRENT: Cell<*const Worker> = const { Cell::new(ptr::null()) };
 }
 
 // スレッド開始時に実行する関数の型
-type Entry = fn(); // <1>
+// 環境をキャプチャしたクロージャも実行できるようにBox化して保持する
+// ワーカ間で移動するためSendである必要がある
+// 戻り値はスレッドの終了理由
+type Entry = Box<dyn FnOnce() -> ExitReason + Send>; // <1>
+
+// スレッドの戻り値の格納先。パニックした場合はそのペイロードを格納する
+type Slot<R> = Arc<Mutex<Option<thread::Result<R>>>>;
 
 // ページサイズ。Linuxだと4KiB
 const PAGE_SIZE: usize = 4 * 1024; // 4KiB <2>
 
+#[rustfmt::skip]
 struct MappedList<T> { // <1>
     map: HashMap<u64, LinkedList<T>>,
 }
@@ -83,17 +66,22 @@ impl<T> MappedList<T> {
         }
     }
 
-    // keyに対応するリストの一番前から取り出す <3>
-    fn pop_front(&mut self, key: u64) -> Option<T> {
-        if let Some(list) = self.map.get_mut(&key) {
-            let val = list.pop_front();
-            if list.len() == 0 {
-                self.map.remove(&key);
-            }
-            val
-        } else {
-            None
+    // keyに対応するリストから、predを満たす最初の要素を取り出す <3>
+    fn remove_first(&mut self, key: u64, pred: impl FnMut(&T) -> bool) -> Option<T> {
+        let list = self.map.get_mut(&key)?;
+        let pos = list.iter().position(pred)?;
+        let mut rest = list.split_off(pos);
+        let val = rest.pop_front();
+        list.append(&mut rest);
+        if list.is_empty() {
+            self.map.remove(&key);
         }
+        val
+    }
+
+    // keyに対応するリストを削除
+    fn remove(&mut self, key: u64) -> Option<LinkedList<T>> {
+        self.map.remove(&key)
     }
 
     fn clear(&mut self) {
@@ -101,13 +89,46 @@ impl<T> MappedList<T> {
     }
 }
 
+// メッセージ。任意の型の値を格納し、受信時に型で選択する
+type Message = Box<dyn Any + Send>;
+
 // コンテキスト <3>
 struct Context {
     regs: Registers,      // レジスタ
     stack: *mut u8,       // スタック
     stack_layout: Layout, // スタックレイアウト
-    entry: Entry,         // エントリポイント
+    lazy: bool,           // スタックをmmapで予約した場合true
+    entry: Option<Entry>, // エントリポイント。実行開始時に取り出す
     id: u64,              // スレッドID
+    exit: ExitReason,     // 終了理由。終了時にentry_pointが設定する
+    stats: Arc<Stats>,    // スレッドの状態。State::statsと共有する
+}
+
+// スタックは各コンテキストが占有しており、
+// 実行中でないコンテキストは別のワーカに移動して再開できる
+unsafe impl Send for Context {}
+
+impl Drop for Context {
+    // スタック領域を解放
+    // 実行中のスタック上で解放しないよう、スケジューラのコンテキストで破棄すること
+    fn drop(&mut self) {
+        unsafe {
+            if self.lazy {
+                munmap(self.stack as *mut c_void, self.stack_layout.size()).unwrap();
+                return;
+            }
+
+            // スタック領域の保護を解除
+            mprotect(
+                self.stack as *mut c_void,
+                PAGE_SIZE,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            )
+            .unwrap();
+            // スタック領域解放
+            dealloc(self.stack, self.stack_layout);
+        }
+    }
 }
 
 impl Context {
@@ -120,211 +141,1712 @@ impl Context {
         &self.regs as *const Registers
     }
 
-    fn new(func: Entry, stack_size: usize, id: u64) -> Self { // <4>
+    #[inline(never)]
+    #[rustfmt::skip]
+    fn new(func: Entry, stack_size: usize, id: u64, lazy: bool, stats: Arc<Stats>) -> Self { // <4>
         // スタック領域の確保 <5>
         let layout = Layout::from_size_align(stack_size, PAGE_SIZE).unwrap();
-        let stack = unsafe { alloc(layout) };
+        let stack = if lazy {
+            // 仮想アドレス空間のみを予約し、物理ページは初めてアクセスした時点でカーネルが割り当てる
+            // 未使用のページを保護しておきSIGSEGVで確保する方式は、
+            // スタック上のバッファをシステムコールに渡した際にEFAULTとなるため用いない
+            unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    stack_size,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+                    -1,
+                    0,
+                )
+                .unwrap() as *mut u8
+            }
+        } else {
+            unsafe { alloc(layout) }
+        };
 
         // ガードページの設定 <6>
         unsafe { mprotect(stack as *mut c_void, PAGE_SIZE, ProtFlags::PROT_NONE).unwrap() };
 
         // レジスタの初期化 <7>
-        let regs = Registers::new(stack as u64 + stack_size as u64);
+        let regs = Registers::new(stack as u64 + stack_size as u64, entry_point);
 
         // コンテキストの初期化
         Context {
-            regs: regs,
-            stack: stack,
+            regs,
+            stack,
             stack_layout: layout,
-            entry: func,
-            id: id,
+            lazy,
+            entry: Some(func),
+            id,
+            exit: ExitReason::Normal,
+            stats,
         }
     }
 }
 
-fn get_id() -> u64 {
-    loop {
-        let rnd = rand::random::<u64>(); // <1>
-        unsafe {
-            if !(*ID).contains(&rnd) { // <2>
-                (*ID).insert(rnd); // <3>
-                return rnd;
+// スレッドの終了を待ち、その戻り値を受け取るためのハンドル
+pub struct JoinHandle<R> {
+    id: u64,         // 対象スレッドのID
+    result: Slot<R>, // 戻り値の格納先。スレッド終了時に書き込まれる
+}
+
+impl<R> JoinHandle<R> {
+    // 対象スレッドのID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // 対象スレッドに型Mのメッセージを送信するためのアドレス
+    pub fn address<M: Send + 'static>(&self) -> Address<M> {
+        Address::new(self.id)
+    }
+
+    // 対象スレッドの終了を待ち、戻り値を返す
+    // 終了していない場合は、終了するまで待機状態に移行して他のスレッドを実行する
+    // 対象スレッドがパニックした場合は、呼び出し元のスレッドでパニックを再開する
+    pub fn join(self) -> R {
+        loop {
+            let result = {
+                let w = current_worker();
+                let mut state = w.rt().state.lock().unwrap();
+                let result = self.result.lock().unwrap().take();
+                if result.is_none() {
+                    state.joining.insert(self.id, w.current_id());
+                }
+                result
             };
+
+            match result {
+                Some(Ok(r)) => return r,
+                Some(Err(payload)) => resume_panic(payload),
+                None => park_as(ThreadState::Joining),
+            }
         }
     }
 }
 
-pub fn spawn(func: Entry, stack_size: usize) -> u64 { // <1>
-    unsafe {
-        let id = get_id(); // <2>
-        CONTEXTS.push_back(Box::new(Context::new(func, stack_size, id))); // <3>
-        schedule(); // <4>
-        id // <5>
+// 型Mのメッセージの宛先
+// 同じスレッドに異なる型のアドレスを複数作ることができ、受信側はrecv::<M>()で型ごとに受信する
+pub struct Address<M> {
+    id: u64,
+    _marker: PhantomData<fn(M)>,
+}
+
+// 宛先のIDのみを保持するため、Mによらずコピーできる
+impl<M> Clone for Address<M> {
+    fn clone(&self) -> Self {
+        *self
     }
 }
 
-pub fn schedule() {
-    unsafe {
-        // 実行可能なプロセスが自身のみであるため即座にリターン <1>
-        if CONTEXTS.len() == 1 {
+impl<M> Copy for Address<M> {}
+
+impl<M: Send + 'static> Address<M> {
+    fn new(id: u64) -> Self {
+        Address {
+            id,
+            _marker: PhantomData,
+        }
+    }
+
+    // 宛先のスレッドID
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // メッセージを送信
+    // 宛先のスレッドがすでに終了している場合は破棄される
+    pub fn send(&self, msg: M) {
+        send(self.id, Box::new(msg));
+    }
+}
+
+// スレッドの終了理由
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    Normal,        // 正常終了
+    Panic(String), // パニックした。パニック時のメッセージを保持する
+    Killed,        // killにより終了させられた
+    NoProc,        // link・monitorの時点で対象スレッドが存在しなかった
+}
+
+impl ExitReason {
+    fn from_payload(payload: &(dyn Any + Send)) -> Self {
+        if payload.is::<Killed>() {
+            ExitReason::Killed
+        } else if let Some(msg) = payload.downcast_ref::<&str>() {
+            ExitReason::Panic(msg.to_string())
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            ExitReason::Panic(msg.clone())
+        } else {
+            ExitReason::Panic("Box<dyn Any>".to_string())
+        }
+    }
+}
+
+// リンク・モニタしているスレッドの終了通知
+// recv::<Exit>()で受信する
+#[derive(Clone, Debug)]
+pub struct Exit {
+    pub id: u64,            // 終了したスレッドのID
+    pub reason: ExitReason, // 終了理由
+}
+
+// killにより終了させる際のパニックのペイロード
+struct Killed;
+
+// joinなどで受け取ったパニックを、呼び出し元のスレッドで再開する
+fn resume_panic(payload: Box<dyn Any + Send>) -> ! {
+    if payload.is::<Killed>() {
+        panic!("joined thread was killed");
+    }
+    panic::resume_unwind(payload)
+}
+
+// スレッドの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,   // 実行中
+    Runnable,  // 実行キューで実行を待っている
+    Receiving, // メッセージの受信待ち
+    Sleeping,  // sleepで待機中
+    Joining,   // 他のスレッドの終了待ち
+    WaitingIo, // I/O待ち
+    Parked,    // 同期プリミティブなど、その他の理由で待機中
+}
+
+impl ThreadState {
+    const ALL: [ThreadState; 7] = [
+        ThreadState::Running,
+        ThreadState::Runnable,
+        ThreadState::Receiving,
+        ThreadState::Sleeping,
+        ThreadState::Joining,
+        ThreadState::WaitingIo,
+        ThreadState::Parked,
+    ];
+}
+
+// スレッドの情報
+pub struct ThreadInfo {
+    pub id: u64,
+    pub state: ThreadState,
+    pub stack_size: usize,
+    pub switches: u64, // このスレッドに切り替えた回数
+}
+
+// スレッドごとの統計情報
+// ワーカはstateのロックを獲得せずに更新する
+struct Stats {
+    state: AtomicU8, // ThreadState::ALLのインデックス
+    switches: AtomicU64,
+    stack_size: usize,
+}
+
+impl Stats {
+    fn new(stack_size: usize) -> Self {
+        Stats {
+            state: AtomicU8::new(ThreadState::Runnable as u8),
+            switches: AtomicU64::new(0),
+            stack_size,
+        }
+    }
+
+    fn set(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> ThreadState {
+        ThreadState::ALL[self.state.load(Ordering::Relaxed) as usize]
+    }
+}
+
+// 実行中のスレッドがスケジューラに制御を戻す理由
+#[derive(Clone, Copy)]
+enum Action {
+    Yield,             // 実行可能なまま実行キューに戻る
+    Park(ThreadState), // 待機状態に移行する。待機の理由を保持する
+    Exit,              // 終了する
+}
+
+// すべてのワーカで共有する状態
+struct State {
+    id: HashSet<u64>,                            // スレッドIDの集合
+    mailboxes: MappedList<Message>,              // スレッドごとのメッセージキュー
+    waiting: HashMap<u64, Box<Context>>,         // 待機スレッド集合
+    timers: BinaryHeap<Reverse<(Instant, u64)>>, // 待機の期限とスレッドID。期限が近い順に取り出す
+    deadlines: HashMap<u64, Instant>,            // 期限付きで待機しているスレッドの期限
+    joining: HashMap<u64, u64>, // join待ちのスレッド。対象スレッドのIDから待っているスレッドのIDへの写像
+    notified: HashSet<u64>,     // 待機状態に移行する前に起床を要求されたスレッド
+    links: HashMap<u64, HashSet<u64>>, // リンクしているスレッド。双方向に登録する
+    monitors: HashMap<u64, HashSet<u64>>, // 対象スレッドのIDから、モニタしているスレッドのIDの集合への写像
+    killed: HashSet<u64>,                 // killされ、まだ終了していないスレッド
+    io: HashMap<RawFd, IoWaiters>,        // fdごとのI/O待ちのスレッド
+    stats: HashMap<u64, Arc<Stats>>,      // 終了していないスレッドの統計情報
+    threads: usize,                       // 終了していないスレッド数
+    deadlock: bool,                       // デッドロックを検知した場合true
+}
+
+// fdが読み書き可能になるのを待っているスレッド
+// 同じfdを複数のスレッドが待つ場合もあるため、方向ごとにすべて保持する
+#[derive(Default)]
+struct IoWaiters {
+    readers: Vec<u64>, // 読み込み可能になるのを待つスレッド
+    writers: Vec<u64>, // 書き込み可能になるのを待つスレッド
+}
+
+impl IoWaiters {
+    fn slot(&mut self, write: bool) -> &mut Vec<u64> {
+        if write {
+            &mut self.writers
+        } else {
+            &mut self.readers
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.readers.is_empty() && self.writers.is_empty()
+    }
+}
+
+impl State {
+    fn get_id(&mut self) -> u64 {
+        loop {
+            let rnd = rand::random::<u64>();
+            if self.id.insert(rnd) {
+                return rnd;
+            }
+        }
+    }
+}
+
+// グリーンスレッドのランタイム
+// ワーカごとの実行キューと、待機スレッド集合、メッセージキューを保持する
+// 各ワーカは1つのOSスレッドで動作し、自身のキューが空になると他のワーカのキューから盗む
+// OSスレッドごとに別々のランタイムを実行することもできる
+pub struct Runtime {
+    queues: Vec<Mutex<VecDeque<Box<Context>>>>, // ワーカごとの実行キュー
+    state: Mutex<State>,
+    idle: Condvar,                // 実行可能なスレッドがないワーカの待機用
+    sleeping: AtomicUsize,        // idleで待機中のワーカ数。stateのロックを獲得して更新する
+    timers: AtomicUsize,          // state.timersの要素数。stateのロックを獲得して更新する
+    kills: AtomicUsize,           // state.killedの要素数。stateのロックを獲得して更新する
+    io: AtomicUsize,              // state.ioに登録されたスレッド数。stateのロックを獲得して更新する
+    epfd: RawFd,                  // I/O待ちのfdを監視するepollのfd
+    event: RawFd,                 // epoll_waitで待機中のワーカを起床させるためのeventfd
+    polling: AtomicBool,          // epoll_waitで待機中のワーカがいる場合true
+    time_slice: Option<Duration>, // プリエンプションのタイムスライス。Noneなら協調的に切り替える
+    lazy_stacks: bool, // スタックをmmapで予約し、アクセスされたページのみ物理メモリを割り当てる
+    switches: AtomicU64, // コンテキストスイッチの回数
+    starvations: AtomicU64, // 切り替え要求を無視し続けるスレッドを検知した回数
+    trace: Option<Trace>, // イベントのトレース。Noneなら記録しない
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        close(self.epfd).unwrap();
+        close(self.event).unwrap();
+    }
+}
+
+impl Runtime {
+    // 呼び出し元のOSスレッドのみで実行するランタイムを生成
+    pub fn new() -> Self {
+        Self::with_workers(1)
+    }
+
+    // ワーカ数を指定してランタイムを生成
+    // 呼び出し元のOSスレッドと、workers - 1個の新たなOSスレッドがワーカとなる
+    pub fn with_workers(workers: usize) -> Self {
+        assert!(workers > 0, "number of workers must be positive");
+
+        // eventfdをepollの監視対象に追加
+        // 他のワーカがeventfdに書き込むと、epoll_waitで待機中のワーカが起床する
+        let epfd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).unwrap();
+        let event = eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK).unwrap();
+        let mut ev = EpollEvent::new(EpollFlags::EPOLLIN, event as u64);
+        epoll_ctl(epfd, EpollOp::EpollCtlAdd, event, &mut ev).unwrap();
+
+        Runtime {
+            queues: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+            state: Mutex::new(State {
+                id: HashSet::new(),
+                mailboxes: MappedList::new(),
+                waiting: HashMap::new(),
+                timers: BinaryHeap::new(),
+                deadlines: HashMap::new(),
+                joining: HashMap::new(),
+                notified: HashSet::new(),
+                links: HashMap::new(),
+                monitors: HashMap::new(),
+                killed: HashSet::new(),
+                io: HashMap::new(),
+                stats: HashMap::new(),
+                threads: 0,
+                deadlock: false,
+            }),
+            idle: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+            timers: AtomicUsize::new(0),
+            kills: AtomicUsize::new(0),
+            io: AtomicUsize::new(0),
+            epfd,
+            event,
+            polling: AtomicBool::new(false),
+            time_slice: None,
+            lazy_stacks: false,
+            switches: AtomicU64::new(0),
+            starvations: AtomicU64::new(0),
+            trace: None,
+        }
+    }
+
+    // タイマ割り込みによるプリエンプションを有効化
+    // 各ワーカで、スレッドがCPU時間をslice消費するごとに切り替えを要求し、
+    // スレッドが次に安全な切り替え点を通過した時点で他のスレッドに切り替える
+    // 切り替え点を通過しない処理(関数呼び出しのないループなど)は切り替えられず、
+    // 同じワーカの他のスレッドは実行されない。そのようなスレッドは、
+    // 一定数のタイムスライスを続けて消費した時点で標準エラー出力に報告する
+    pub fn preemptive(mut self, slice: Duration) -> Self {
+        assert!(!slice.is_zero(), "time slice must be positive");
+        self.time_slice = Some(slice);
+        self
+    }
+
+    // スタックを遅延確保するモードを有効化
+    // スタックサイズ分の仮想アドレス空間のみを予約するため、大きなスタックのスレッドを多数生成できる
+    pub fn lazy_stacks(mut self) -> Self {
+        self.lazy_stacks = true;
+        self
+    }
+
+    // スレッドの生成・実行・待機・起床・終了のイベントを記録するモードを有効化
+    // 記録したイベントはtrace_jsonで取得する
+    pub fn tracing(mut self) -> Self {
+        self.trace = Some(Trace::new());
+        self
+    }
+
+    // 記録したイベントをChrome trace形式のJSONで返す
+    // トレースが無効な場合はNone
+    pub fn trace_json(&self) -> Option<String> {
+        self.trace.as_ref().map(|trace| trace.to_json())
+    }
+
+    // イベントを記録
+    fn record(&self, worker: usize, id: u64, kind: EventKind) {
+        if let Some(trace) = &self.trace {
+            trace.record(worker, id, kind);
+        }
+    }
+
+    pub fn workers(&self) -> usize {
+        self.queues.len()
+    }
+
+    // 最初のスレッドを起動し、すべてのスレッドが終了するまで待機
+    // 最初のスレッドの戻り値を返す
+    pub fn run<F, R>(&self, func: F, stack_size: usize) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (entry, result) = new_entry(func);
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.threads > 0 {
+                panic!("runtime is already running");
+            }
+            let id = state.get_id();
+            state.threads = 1;
+            let stats = Arc::new(Stats::new(stack_size));
+            state.stats.insert(id, stats.clone());
+            self.record(0, id, EventKind::Spawn);
+            self.queues[0]
+                .lock()
+                .unwrap()
+                .push_back(Box::new(Context::new(
+                    entry,
+                    stack_size,
+                    id,
+                    self.lazy_stacks,
+                    stats,
+                )));
+        }
+
+        // 呼び出し元のOSスレッドをワーカ0とし、残りのワーカを起動
+        thread::scope(|s| {
+            for index in 1..self.workers() {
+                s.spawn(move || Worker::new(index, self).run());
+            }
+            Worker::new(0, self).run();
+        });
+
+        // 状態をクリア
+        // デッドロックした場合は待機中のスレッドが残っているため、そのスタックも解放される
+        let deadlock = {
+            let mut state = self.state.lock().unwrap();
+            state.id.clear();
+            state.mailboxes.clear();
+            state.waiting.clear();
+            state.timers.clear();
+            state.deadlines.clear();
+            state.joining.clear();
+            state.notified.clear();
+            state.links.clear();
+            state.monitors.clear();
+            state.killed.clear();
+            state.io.clear();
+            state.stats.clear();
+            state.threads = 0;
+            self.timers.store(0, Ordering::SeqCst);
+            self.kills.store(0, Ordering::SeqCst);
+            self.io.store(0, Ordering::SeqCst);
+            std::mem::replace(&mut state.deadlock, false)
+        };
+        if deadlock {
+            panic!("deadlock");
+        }
+
+        // 最初のスレッドがパニックした場合は、呼び出し元でパニックを再開する
+        let result = result.lock().unwrap().take();
+        match result.unwrap() {
+            Ok(r) => r,
+            Err(payload) => resume_panic(payload),
+        }
+    }
+
+    // 待機状態のスレッドを、呼び出し元のワーカの実行キューに移動
+    // 待機状態に移行する前の場合は、移行時に即座に起床するよう記録する
+    fn wake(&self, state: &mut State, index: usize, key: u64) {
+        if let Some(ctx) = state.waiting.remove(&key) {
+            ctx.stats.set(ThreadState::Runnable);
+            self.record(index, key, EventKind::Wake);
+            self.queues[index].lock().unwrap().push_back(ctx);
+            if self.sleeping.load(Ordering::SeqCst) > 0 {
+                self.notify_one();
+            }
+        } else if state.id.contains(&key) {
+            state.notified.insert(key);
+        }
+    }
+
+    // 待機中のワーカを起床させる
+    // epoll_waitで待機中のワーカがいれば、eventfdに書き込んで起床させる
+    fn notify_one(&self) {
+        self.idle.notify_one();
+        self.interrupt_poll();
+    }
+
+    fn notify_all(&self) {
+        self.idle.notify_all();
+        self.interrupt_poll();
+    }
+
+    fn interrupt_poll(&self) {
+        if self.polling.load(Ordering::SeqCst) {
+            write(self.event, &1u64.to_ne_bytes()).unwrap();
+        }
+    }
+
+    // fdのI/O待ちのスレッドに応じて、epollの監視対象を設定
+    // EPOLLONESHOTを指定するため、イベントが発生するたびに再設定する
+    fn arm(&self, fd: RawFd, waiters: &IoWaiters) {
+        let mut flags = EpollFlags::EPOLLONESHOT;
+        if !waiters.readers.is_empty() {
+            flags |= EpollFlags::EPOLLIN;
+        }
+        if !waiters.writers.is_empty() {
+            flags |= EpollFlags::EPOLLOUT;
+        }
+        let mut ev = EpollEvent::new(flags, fd as u64);
+
+        // 待機中のスレッドがなければ監視対象から削除
+        // fdがすでに閉じられている場合もあるため、エラーは無視する
+        if waiters.is_empty() {
+            epoll_ctl(self.epfd, EpollOp::EpollCtlDel, fd, &mut ev).ok();
             return;
         }
 
-        // 自身のコンテキストを実行キューの最後に移動
-        let mut ctx = CONTEXTS.pop_front().unwrap(); // <2>
-        // レジスタ保存領域へのポインタを取得 <3>
-        let regs = ctx.get_regs_mut();
-        CONTEXTS.push_back(ctx);
+        match epoll_ctl(self.epfd, EpollOp::EpollCtlMod, fd, &mut ev) {
+            Ok(()) => (),
+            Err(nix::Error::Sys(Errno::ENOENT)) => {
+                // 監視対象に追加されていない場合は追加
+                epoll_ctl(self.epfd, EpollOp::EpollCtlAdd, fd, &mut ev).unwrap();
+            }
+            Err(err) => panic!("epoll_ctl: {}", err),
+        }
+    }
 
-        // レジスタを保存 <4>
-        if set_context(regs) == 0 {
-            // 次のスレッドにコンテキストスイッチ
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
+    // 期限付きで待機するスレッドを登録
+    // 期限を過ぎるとfire_timersにより起床される
+    fn add_timer(&self, state: &mut State, key: u64, deadline: Instant) {
+        if state.deadlines.insert(key, deadline) != Some(deadline) {
+            state.timers.push(Reverse((deadline, key)));
+            self.timers.fetch_add(1, Ordering::SeqCst);
         }
+    }
 
-        // 不要なスタック領域を削除
-        rm_unused_stack(); // <5>
+    // メッセージキューに追加し、受信待ちの場合は起床させる
+    // 宛先のスレッドが終了している場合はメッセージを返す
+    fn deliver(
+        &self,
+        state: &mut State,
+        index: usize,
+        key: u64,
+        msg: Message,
+    ) -> Result<(), Message> {
+        if !state.id.contains(&key) {
+            return Err(msg);
+        }
+        state.mailboxes.push_back(key, msg);
+        self.wake(state, index, key);
+        Ok(())
     }
 }
 
-extern "C" fn entry_point() {
-    unsafe {
-        // 指定されたエントリ関数を実行 <1>
-        let ctx = CONTEXTS.front().unwrap();
-        ((**ctx).entry)();
+// ワーカ
+// OSスレッドごとに1つ存在し、スケジューラのコンテキストで実行キューのスレッドを順に実行する
+// グリーンスレッドは切り替え時に一旦スケジューラのコンテキストに戻るため、
+// 実行キューに戻されたスレッドが、スタックを使用中に他のワーカに盗まれることはない
+struct Worker {
+    index: usize,                           // ワーカの番号。実行キューの位置
+    rt: *const Runtime,                     // 所属するランタイム
+    regs: UnsafeCell<Registers>,            // スケジューラのコンテキスト
+    current: RefCell<Option<Box<Context>>>, // 実行中のスレッド
+    action: Cell<Action>,                   // 実行中のスレッドが制御を戻した理由
+}
 
-        // 以降がスレッド終了時の後処理
+impl Worker {
+    fn new(index: usize, rt: &Runtime) -> Self {
+        Worker {
+            index,
+            rt: rt as *const Runtime,
+            regs: UnsafeCell::new(Registers::new(0, entry_point)),
+            current: RefCell::new(None),
+            action: Cell::new(Action::Yield),
+        }
+    }
 
-        // 自身のコンテキストを取り除く
-        let ctx = CONTEXTS.pop_front().unwrap();
+    fn rt(&self) -> &Runtime {
+        unsafe { &*self.rt }
+    }
 
-        // スレッドIDを削除
-        (*ID).remove(&ctx.id);
+    // 実行中のスレッドのID
+    fn current_id(&self) -> u64 {
+        self.current.borrow().as_ref().unwrap().id
+    }
+
+    // 自身の実行キューの最後尾に追加
+    // 待機中のワーカがいれば、盗めるように起床させる
+    fn push(&self, ctx: Box<Context>) {
+        let rt = self.rt();
+        ctx.stats.set(ThreadState::Runnable);
+        rt.queues[self.index].lock().unwrap().push_back(ctx);
+        if rt.sleeping.load(Ordering::SeqCst) > 0 {
+            let _state = rt.state.lock().unwrap();
+            rt.notify_one();
+        }
+    }
 
-        // 不要なスタック領域として保存
-        // この段階で解放すると、以降のコードでスタックが使えなくなる
-        UNUSED_STACK = ((*ctx).stack, (*ctx).stack_layout); // <2>
+    // 次に実行するスレッドを取得
+    // 自身のキューの先頭から取り出し、空ならば他のワーカのキューの最後尾から盗む
+    fn find_work(&self) -> Option<Box<Context>> {
+        let rt = self.rt();
+        if let Some(ctx) = rt.queues[self.index].lock().unwrap().pop_front() {
+            return Some(ctx);
+        }
+
+        let n = rt.workers();
+        (1..n).find_map(|i| rt.queues[(self.index + i) % n].lock().unwrap().pop_back())
+    }
+
+    // 期限を過ぎた受信待ち・スリープ中のスレッドを起床
+    fn fire_timers(&self) {
+        let rt = self.rt();
+        if rt.timers.load(Ordering::SeqCst) == 0 {
+            return;
+        }
 
-        match CONTEXTS.front() { // <3>
-            Some(c) => {
-                // 次のスレッドにコンテキストスイッチ
-                switch_context((**c).get_regs());
+        let mut state = rt.state.lock().unwrap();
+        let now = Instant::now();
+        while let Some(&Reverse((deadline, key))) = state.timers.peek() {
+            if deadline > now {
+                break;
             }
-            None => {
-                // すべてのスレッドが終了した場合、main関数のスレッドに戻る
-                if let Some(c) = &CTX_MAIN {
-                    switch_context(&**c as *const Registers);
+            state.timers.pop();
+            rt.timers.fetch_sub(1, Ordering::SeqCst);
+
+            // すでにメッセージを受信するなどして期限が取り消されている場合は何もしない
+            if state.deadlines.get(&key) == Some(&deadline) {
+                rt.wake(&mut state, self.index, key);
+            }
+        }
+    }
+
+    // epollでI/O待ちのfdのイベントを待ち、対象のスレッドを起床
+    // timeoutがNoneの場合は、イベントが発生するかeventfdに書き込まれるまで待機
+    fn poll_io(&self, timeout: Option<Duration>) {
+        let rt = self.rt();
+        let timeout_ms = match timeout {
+            // 期限を過ぎてから起床するよう切り上げる
+            Some(t) => t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as isize,
+            None => -1,
+        };
+
+        // シグナルで中断された場合はイベントなしとして扱う
+        let mut events = [EpollEvent::empty(); 64];
+        let n = epoll_wait(rt.epfd, &mut events, timeout_ms).unwrap_or(0);
+
+        let mut state = rt.state.lock().unwrap();
+        for ev in &events[..n] {
+            let fd = ev.data() as RawFd;
+            if fd == rt.event {
+                // eventfdの通知を解除
+                let mut buf = [0; 8];
+                read(rt.event, &mut buf).ok();
+                continue;
+            }
+
+            let Some(waiters) = state.io.get_mut(&fd) else {
+                continue;
+            };
+
+            // エラーや切断の場合は、読み書きの両方を起床させて結果を確認させる
+            // 同じ方向を待つスレッドはすべて起床させ、操作に失敗したスレッドは再度待機する
+            let flags = ev.events();
+            let err = flags.intersects(EpollFlags::EPOLLERR | EpollFlags::EPOLLHUP);
+            let readers = if err || flags.contains(EpollFlags::EPOLLIN) {
+                std::mem::take(&mut waiters.readers)
+            } else {
+                Vec::new()
+            };
+            let writers = if err || flags.contains(EpollFlags::EPOLLOUT) {
+                std::mem::take(&mut waiters.writers)
+            } else {
+                Vec::new()
+            };
+
+            // 起床させないスレッドのために再設定
+            rt.arm(fd, waiters);
+            if waiters.is_empty() {
+                state.io.remove(&fd);
+            }
+
+            for key in readers.into_iter().chain(writers) {
+                rt.io.fetch_sub(1, Ordering::SeqCst);
+                rt.wake(&mut state, self.index, key);
+            }
+        }
+    }
+
+    // 実行中のスレッドから切り替える必要がある場合true
+    // 自身のキューが空でも、期限付きの待機やI/O待ちがあれば、スケジューラに戻って確認する
+    fn should_switch(&self) -> bool {
+        let rt = self.rt();
+        rt.timers.load(Ordering::SeqCst) > 0
+            || rt.io.load(Ordering::SeqCst) > 0
+            || !rt.queues[self.index].lock().unwrap().is_empty()
+    }
+
+    // いずれかのワーカのキューに実行可能なスレッドがある場合true
+    fn has_work(&self) -> bool {
+        self.rt()
+            .queues
+            .iter()
+            .any(|queue| !queue.lock().unwrap().is_empty())
+    }
+
+    // 実行可能なスレッドができるまで待機
+    // すべてのスレッドが終了した場合とデッドロックした場合はfalseを返す
+    fn wait_for_work(&self) -> bool {
+        let rt = self.rt();
+        let state = rt.state.lock().unwrap();
+        if state.threads == 0 || state.deadlock {
+            return false;
+        }
+
+        // 待機中であることを示してから実行キューを確認することで、
+        // pushによる起床要求を取りこぼさないようにする
+        let sleeping = rt.sleeping.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.has_work() {
+            rt.sleeping.fetch_sub(1, Ordering::SeqCst);
+            return true;
+        }
+
+        // 期限付きで待機しているスレッドがあれば、最も近い期限まで待機
+        let timeout = match state.timers.peek() {
+            Some(&Reverse((deadline, _))) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    rt.sleeping.fetch_sub(1, Ordering::SeqCst);
+                    return true;
                 }
+                Some(deadline - now)
             }
+            None => None,
         };
+
+        // I/O待ちのスレッドがあれば、1つのワーカのみがepollで待機する
+        // 他のワーカは、実行可能なスレッドができた時点でeventfdにより起床させる
+        if !state.io.is_empty() && !rt.polling.load(Ordering::SeqCst) {
+            rt.polling.store(true, Ordering::SeqCst);
+            drop(state);
+            self.poll_io(timeout);
+            rt.polling.store(false, Ordering::SeqCst);
+            rt.sleeping.fetch_sub(1, Ordering::SeqCst);
+            return true;
+        }
+
+        match timeout {
+            Some(timeout) => {
+                let _state = rt.idle.wait_timeout(state, timeout).unwrap();
+            }
+            None => {
+                // 実行中のスレッドも、期限付きやI/Oで待機しているスレッドもなく、
+                // すべてのワーカが待機しようとしている場合はデッドロック
+                if sleeping == rt.workers() && state.io.is_empty() {
+                    let mut state = state;
+                    state.deadlock = true;
+                    rt.sleeping.fetch_sub(1, Ordering::SeqCst);
+                    rt.notify_all();
+                    return false;
+                }
+
+                let _state = rt.idle.wait(state).unwrap();
+            }
+        }
+        rt.sleeping.fetch_sub(1, Ordering::SeqCst);
+        true
     }
-    panic!("entry_point"); // <4>
+
+    // スケジューラのメインループ
+    fn run(&self) {
+        CURRENT.with(|current| {
+            // 同じOSスレッドで別のランタイムが実行中ならエラーとする
+            if !current.get().is_null() {
+                panic!("a green thread runtime is already running on this thread");
+            }
+            current.set(self as *const Worker);
+        });
+
+        // プリエンプションが有効なら、このワーカのOSスレッドにタイマを設定
+        let timer = self.rt().time_slice.map(preempt::Timer::start);
+
+        // スタックオーバーフローを検知するため、SIGSEGVのハンドラ用のスタックを設定
+        let alt_stack = overflow::AltStack::new();
+
+        loop {
+            self.fire_timers();
+
+            // I/O待ちのスレッドがあれば、ブロックせずにイベントを確認
+            if self.rt().io.load(Ordering::SeqCst) > 0 {
+                self.poll_io(Some(Duration::ZERO));
+            }
+
+            let ctx = match self.find_work() {
+                Some(ctx) => ctx,
+                None => {
+                    if self.wait_for_work() {
+                        continue;
+                    }
+                    break;
+                }
+            };
+
+            // 選んだスレッドにコンテキストスイッチ
+            // switch_contextは戻らない関数として扱われるため、
+            // 下のif文の中で値をムーブすると、戻ってきた後に二重に解放されてしまう
+            let rt = self.rt();
+            ctx.stats.set(ThreadState::Running);
+            ctx.stats.switches.fetch_add(1, Ordering::Relaxed);
+            rt.switches.fetch_add(1, Ordering::Relaxed);
+            rt.record(self.index, ctx.id, EventKind::Begin);
+
+            // スケジューラの実行中に要求された切り替えは、これから実行するスレッドには適用しない
+            preempt::take_resched();
+
+            let next = ctx.get_regs();
+            *self.current.borrow_mut() = Some(ctx);
+            unsafe {
+                if set_context(self.regs.get()) == 0 {
+                    switch_context(next);
+                }
+            }
+
+            // スレッドが制御を戻した理由に応じて後処理
+            let ctx = self.current.borrow_mut().take().unwrap();
+            rt.record(self.index, ctx.id, EventKind::End);
+            match self.action.get() {
+                Action::Yield => self.push(ctx),
+                Action::Park(reason) => {
+                    let mut state = rt.state.lock().unwrap();
+                    if state.notified.remove(&ctx.id) {
+                        drop(state);
+                        self.push(ctx);
+                    } else {
+                        ctx.stats.set(reason);
+                        rt.record(self.index, ctx.id, EventKind::Block(reason));
+                        state.waiting.insert(ctx.id, ctx);
+                    }
+                }
+                Action::Exit => {
+                    let mut state = rt.state.lock().unwrap();
+                    let reason = match ctx.exit {
+                        ExitReason::Normal => "normal",
+                        ExitReason::Panic(_) => "panic",
+                        ExitReason::Killed => "killed",
+                        ExitReason::NoProc => "noproc",
+                    };
+                    rt.record(self.index, ctx.id, EventKind::Exit(reason));
+
+                    // スレッドIDを削除
+                    state.id.remove(&ctx.id);
+                    state.stats.remove(&ctx.id);
+                    state.notified.remove(&ctx.id);
+                    state.deadlines.remove(&ctx.id);
+                    if state.killed.remove(&ctx.id) {
+                        rt.kills.fetch_sub(1, Ordering::SeqCst);
+                    }
+
+                    // リンク・モニタしているスレッドに終了を通知
+                    let mut watchers = state.monitors.remove(&ctx.id).unwrap_or_default();
+                    if let Some(links) = state.links.remove(&ctx.id) {
+                        for other in &links {
+                            if let Some(set) = state.links.get_mut(other) {
+                                set.remove(&ctx.id);
+                                if set.is_empty() {
+                                    state.links.remove(other);
+                                }
+                            }
+                        }
+                        watchers.extend(links);
+                    }
+                    for key in watchers {
+                        let exit = Exit {
+                            id: ctx.id,
+                            reason: ctx.exit.clone(),
+                        };
+                        let _ = rt.deliver(&mut state, self.index, key, Box::new(exit));
+                    }
+
+                    // 未受信のメッセージは、ロックを解放してから破棄する
+                    let mailbox = state.mailboxes.remove(ctx.id);
+
+                    // 終了を待っているスレッドを実行キューに移動
+                    if let Some(key) = state.joining.remove(&ctx.id) {
+                        rt.wake(&mut state, self.index, key);
+                    }
+
+                    // すべてのスレッドが終了した場合、待機中のワーカも終了させる
+                    state.threads -= 1;
+                    if state.threads == 0 {
+                        rt.notify_all();
+                    }
+
+                    // スケジューラのスタック上にいるため、ここでスタック領域を解放できる
+                    drop(ctx);
+                    drop(state);
+                    drop(mailbox);
+                }
+            }
+        }
+
+        drop(alt_stack);
+        drop(timer);
+        CURRENT.with(|current| current.set(ptr::null()));
+    }
+}
+
+// このOSスレッドで実行中のワーカ
+// グリーンスレッドはコンテキストスイッチの前後で別のOSスレッドに移動している可能性があるため、
+// 戻り値はコンテキストスイッチをまたいで保持せず、スイッチ後は再度取得すること
+// スレッドローカル変数のアドレスがスイッチをまたいで再利用されないよう、インライン化しない
+#[inline(never)]
+fn current_worker() -> &'static Worker {
+    let w = CURRENT.with(|current| current.get());
+    if w.is_null() {
+        panic!("no green thread runtime is running on this thread");
+    }
+    unsafe { &*w }
 }
 
-pub fn spawn_from_main(func: Entry, stack_size: usize) {
+// 実行中のスレッドのレジスタを保存し、スケジューラのコンテキストに切り替える
+// 再開された時点では、別のワーカで実行されている可能性がある
+fn switch_to_scheduler(action: Action) {
+    let w = current_worker();
+    w.action.set(action);
+    let regs = w.current.borrow_mut().as_mut().unwrap().get_regs_mut();
+    let sched = w.regs.get();
     unsafe {
-        // すでに初期化済みならエラーとする
-        if let Some(_) = &CTX_MAIN {
-            panic!("spawn_from_main is called twice");
-        }
-
-        // main関数用のコンテキストを生成
-        CTX_MAIN = Some(Box::new(Registers::new(0)));
-        if let Some(ctx) = &mut CTX_MAIN {
-            // グローバル変数を初期化 <1>
-            let mut msgs = MappedList::new();
-            MESSAGES = &mut msgs as *mut MappedList<u64>;
-
-            let mut waiting = HashMap::new();
-            WAITING = &mut waiting as *mut HashMap<u64, Box<Context>>;
-
-            let mut ids = HashSet::new();
-            ID = &mut ids as *mut HashSet<u64>;
-
-            // すべてのスレッド終了時の戻り先を保存 <2>
-            if set_context(&mut **ctx as *mut Registers) == 0 {
-                // 最初に起動するスレッドのコンテキストを生成して実行 <3>
-                CONTEXTS.push_back(Box::new(Context::new(func, stack_size, get_id())));
-                let first = CONTEXTS.front().unwrap();
-                switch_context(first.get_regs());
+        if set_context(regs) == 0 {
+            switch_context(sched);
+        }
+    }
+
+    // 再開した時点でkillされていれば終了
+    check_killed();
+}
+
+// 実行中のスレッドがkillされていれば、パニックしてスレッドを終了させる
+fn check_killed() {
+    let w = current_worker();
+    let rt = w.rt();
+    if rt.kills.load(Ordering::SeqCst) == 0 {
+        return;
+    }
+
+    let killed = {
+        let mut state = rt.state.lock().unwrap();
+        let killed = state.killed.remove(&w.current_id());
+        if killed {
+            rt.kills.fetch_sub(1, Ordering::SeqCst);
+        }
+        killed
+    };
+    if killed {
+        // パニックフックを呼び出さずに巻き戻す
+        panic::resume_unwind(Box::new(Killed));
+    }
+}
+
+// アドレスが実行中のスレッドのガードページ内であれば、スレッドIDとスタックサイズを返す
+// SIGSEGVのハンドラから呼び出されるため、ロックの獲得やメモリの確保は行わない
+pub fn guard_page_hit(addr: usize) -> Option<(u64, usize)> {
+    let w = CURRENT.with(|current| current.get());
+    if w.is_null() {
+        return None;
+    }
+
+    // 割り込まれた処理がcurrentを借用中の可能性があるため、借用状態を確認せずに読み込む
+    let w = unsafe { &*w };
+    let ctx = unsafe { (*w.current.as_ptr()).as_ref()? };
+    let guard = ctx.stack as usize;
+    if (guard..guard + PAGE_SIZE).contains(&addr) {
+        Some((ctx.id, ctx.stack_layout.size()))
+    } else {
+        None
+    }
+}
+
+// 実行中のスレッドが切り替え要求を無視し続けていることを記録し、スレッドIDを返す
+// SIGALRMのハンドラから呼び出されるため、ロックの獲得やメモリの確保は行わない
+pub fn report_starving() -> Option<u64> {
+    let w = CURRENT.with(|current| current.get());
+    if w.is_null() {
+        return None;
+    }
+
+    // 割り込まれた処理がcurrentを借用中の可能性があるため、借用状態を確認せずに読み込む
+    let w = unsafe { &*w };
+    let ctx = unsafe { (*w.current.as_ptr()).as_ref()? };
+    w.rt().starvations.fetch_add(1, Ordering::Relaxed);
+    Some(ctx.id)
+}
+
+// I/O待ちの登録
+// 待機から戻る際や、killにより巻き戻される際に解除する
+struct IoWait {
+    fd: RawFd,
+    write: bool,
+    id: u64,
+}
+
+impl Drop for IoWait {
+    fn drop(&mut self) {
+        let rt = current_worker().rt();
+        let mut state = rt.state.lock().unwrap();
+        let Some(waiters) = state.io.get_mut(&self.fd) else {
+            return;
+        };
+
+        // イベントの発生により起床した場合は、すでに解除されている
+        let slot = waiters.slot(self.write);
+        if let Some(i) = slot.iter().position(|id| *id == self.id) {
+            slot.remove(i);
+            rt.io.fetch_sub(1, Ordering::SeqCst);
+            rt.arm(self.fd, waiters);
+            if waiters.is_empty() {
+                state.io.remove(&self.fd);
+            }
+        }
+    }
+}
+
+// fdが読み込み(write = falseの場合)、または書き込み(write = trueの場合)可能になるまで待機
+// メッセージの受信などでも起床するため、呼び出し元は操作を再試行し、再度待機する必要がある
+fn wait_io(fd: RawFd, write: bool) {
+    let _wait = {
+        let w = current_worker();
+        let rt = w.rt();
+        let id = w.current_id();
+        let mut state = rt.state.lock().unwrap();
+        let waiters = state.io.entry(fd).or_default();
+        waiters.slot(write).push(id);
+        rt.io.fetch_add(1, Ordering::SeqCst);
+        rt.arm(fd, waiters);
+        IoWait { fd, write, id }
+    };
+    park_as(ThreadState::WaitingIo);
+}
+
+// fdが読み込み可能になるまで待機
+pub fn wait_readable(fd: RawFd) {
+    wait_io(fd, false);
+}
+
+// fdが書き込み可能になるまで待機
+pub fn wait_writable(fd: RawFd) {
+    wait_io(fd, true);
+}
+
+// プリエンプションの切り替え点
+// タイマ割り込みで切り替えが要求されていれば、実行中のスレッドを実行キューの最後に移動
+// spawn・send・recv・I/Oでも確認するため、ビジーループなどそれらを呼び出さない処理でのみ
+// 定期的に呼び出せばよい
+pub fn preempt_point() {
+    if !preempt::take_resched() {
+        return;
+    }
+
+    // 実行可能なスレッドが自身のみであれば切り替えない
+    let w = current_worker();
+    if !w.should_switch() {
+        return;
+    }
+    switch_to_scheduler(Action::Yield);
+}
+
+// 実行中のスレッドを待機状態に移行し、unparkなどで起床されるまで他のスレッドを実行する
+// メッセージの到着などでも起床するため、呼び出し元は起床後に条件を再確認する必要がある
+pub fn park() {
+    park_as(ThreadState::Parked);
+}
+
+// 待機の理由を指定して待機状態に移行
+fn park_as(reason: ThreadState) {
+    switch_to_scheduler(Action::Park(reason));
+}
+
+// 待機中のスレッドを起床させる
+// 対象のスレッドがまだ待機状態に移行していない場合は、次のparkが即座に戻る
+pub fn unpark(id: u64) {
+    let w = current_worker();
+    let mut state = w.rt().state.lock().unwrap();
+    w.rt().wake(&mut state, w.index, id);
+}
+
+// 実行中のスレッドのID
+pub fn current_id() -> u64 {
+    current_worker().current_id()
+}
+
+// 実行中のランタイムの、終了していないスレッドの一覧をID順に返す
+pub fn threads() -> Vec<ThreadInfo> {
+    let state = current_worker().rt().state.lock().unwrap();
+    let mut threads: Vec<_> = state
+        .stats
+        .iter()
+        .map(|(&id, stats)| ThreadInfo {
+            id,
+            state: stats.get(),
+            stack_size: stats.stack_size,
+            switches: stats.switches.load(Ordering::Relaxed),
+        })
+        .collect();
+    threads.sort_by_key(|t| t.id);
+    threads
+}
+
+// 実行中のランタイムで行われたコンテキストスイッチの回数
+pub fn context_switches() -> u64 {
+    current_worker().rt().switches.load(Ordering::Relaxed)
+}
+
+// 実行中のランタイムで、切り替え要求を無視し続けるスレッドを検知した回数
+pub fn starvations() -> u64 {
+    current_worker().rt().starvations.load(Ordering::Relaxed)
+}
+
+// クロージャをエントリポイントに変換
+// 戻り値はJoinHandleと共有する領域に書き込む
+// パニックはスレッドの境界で捕捉し、終了理由に変換する
+fn new_entry<F, R>(func: F) -> (Entry, Slot<R>)
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry = Box::new(move || {
+        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+            // 実行開始前にkillされていれば即座に終了
+            check_killed();
+            func()
+        }));
+        let reason = match &r {
+            Ok(_) => ExitReason::Normal,
+            Err(payload) => ExitReason::from_payload(payload.as_ref()),
+        };
+        *slot.lock().unwrap() = Some(r);
+        reason
+    });
+    (entry, result)
+}
+
+// 生成したスレッドとの関係
+enum Relation {
+    None,
+    Link,
+    Monitor,
+}
+
+fn spawn_with<F, R>(func: F, stack_size: usize, relation: Relation) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (entry, result) = new_entry(func);
+    let w = current_worker();
+    let (id, stats) = {
+        let mut state = w.rt().state.lock().unwrap();
+        state.threads += 1;
+        let id = state.get_id();
+
+        let stats = Arc::new(Stats::new(stack_size));
+        state.stats.insert(id, stats.clone());
+        w.rt().record(w.index, id, EventKind::Spawn);
+
+        // 実行開始前に登録するため、生成直後に終了しても通知される
+        let me = w.current_id();
+        match relation {
+            Relation::None => (),
+            Relation::Link => {
+                state.links.entry(me).or_default().insert(id);
+                state.links.entry(id).or_default().insert(me);
             }
+            Relation::Monitor => {
+                state.monitors.entry(id).or_default().insert(me);
+            }
+        }
+        (id, stats)
+    };
+    w.push(Box::new(Context::new(
+        entry,
+        stack_size,
+        id,
+        w.rt().lazy_stacks,
+        stats,
+    )));
+    schedule();
+    JoinHandle { id, result }
+}
+
+#[rustfmt::skip]
+pub fn spawn<F, R>(func: F, stack_size: usize) -> JoinHandle<R> // <1>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    spawn_with(func, stack_size, Relation::None)
+}
+
+// スレッドを生成し、リンクする
+pub fn spawn_link<F, R>(func: F, stack_size: usize) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    spawn_with(func, stack_size, Relation::Link)
+}
+
+// スレッドを生成し、モニタする
+pub fn spawn_monitor<F, R>(func: F, stack_size: usize) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    spawn_with(func, stack_size, Relation::Monitor)
+}
 
-            // 不要なスタックを解放 <4>
-            rm_unused_stack();
+// 実行中のスレッドとidのスレッドをリンク
+// 一方が終了すると、もう一方にExitメッセージが送信される
+// 対象スレッドが存在しない場合は、即座にExitReason::NoProcを受信する
+pub fn link(id: u64) {
+    let w = current_worker();
+    let me = w.current_id();
+    if id == me {
+        return;
+    }
 
-            // グローバル変数をクリア
-            CTX_MAIN = None;
-            CONTEXTS.clear();
-            MESSAGES = ptr::null_mut();
-            WAITING = ptr::null_mut();
-            ID = ptr::null_mut();
+    let mut state = w.rt().state.lock().unwrap();
+    if state.id.contains(&id) {
+        state.links.entry(me).or_default().insert(id);
+        state.links.entry(id).or_default().insert(me);
+    } else {
+        let exit = Exit {
+            id,
+            reason: ExitReason::NoProc,
+        };
+        let _ = w.rt().deliver(&mut state, w.index, me, Box::new(exit));
+    }
+}
 
-            msgs.clear(); // <5>
-            waiting.clear();
-            ids.clear();
+// リンクを解除
+pub fn unlink(id: u64) {
+    let w = current_worker();
+    let me = w.current_id();
+    let mut state = w.rt().state.lock().unwrap();
+    for (a, b) in [(me, id), (id, me)] {
+        if let Some(set) = state.links.get_mut(&a) {
+            set.remove(&b);
+            if set.is_empty() {
+                state.links.remove(&a);
+            }
         }
     }
 }
 
-unsafe fn rm_unused_stack() {
-    if UNUSED_STACK.0 != ptr::null_mut() {
-        // スタック領域の保護を解除 <1>
-        mprotect(
-            UNUSED_STACK.0 as *mut c_void,
-            PAGE_SIZE,
-            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-        )
-        .unwrap();
-        // スタック領域解放 <2>
-        dealloc(UNUSED_STACK.0, UNUSED_STACK.1);
-        UNUSED_STACK = (ptr::null_mut(), Layout::new::<u8>());
+// idのスレッドをモニタ
+// 対象スレッドが終了すると、実行中のスレッドにExitメッセージが送信される
+// 対象スレッドが存在しない場合は、即座にExitReason::NoProcを受信する
+pub fn monitor(id: u64) {
+    let w = current_worker();
+    let me = w.current_id();
+    let mut state = w.rt().state.lock().unwrap();
+    if state.id.contains(&id) {
+        state.monitors.entry(id).or_default().insert(me);
+    } else {
+        let exit = Exit {
+            id,
+            reason: ExitReason::NoProc,
+        };
+        let _ = w.rt().deliver(&mut state, w.index, me, Box::new(exit));
     }
 }
 
-pub fn send(key: u64, msg: u64) { // <1>
-    unsafe {
-        // メッセージキューの最後尾に追加
-        (*MESSAGES).push_back(key, msg);
+// モニタを解除
+pub fn demonitor(id: u64) {
+    let w = current_worker();
+    let me = w.current_id();
+    let mut state = w.rt().state.lock().unwrap();
+    if let Some(set) = state.monitors.get_mut(&id) {
+        set.remove(&me);
+        if set.is_empty() {
+            state.monitors.remove(&id);
+        }
+    }
+}
+
+// idのスレッドを終了させる
+// 対象スレッドは、次にschedule・recv・joinなどで切り替わる時点でパニックと同様に巻き戻され、
+// 終了理由はExitReason::Killedとなる
+pub fn kill(id: u64) {
+    let w = current_worker();
+    let rt = w.rt();
+    let mut state = rt.state.lock().unwrap();
+    if !state.id.contains(&id) {
+        return;
+    }
+    if state.killed.insert(id) {
+        rt.kills.fetch_add(1, Ordering::SeqCst);
+    }
 
-        // スレッドが受信待ちの場合に実行キューに移動
-        if let Some(ctx) = (*WAITING).remove(&key) {
-            CONTEXTS.push_back(ctx);
+    // 待機中であれば起床させる
+    rt.wake(&mut state, w.index, id);
+}
+
+pub fn schedule() {
+    let w = current_worker();
+
+    // 実行可能なスレッドが自身のみであるため即座にリターン
+    if !w.should_switch() {
+        return;
+    }
+
+    // 自身を実行キューの最後に移動し、次のスレッドに切り替える
+    switch_to_scheduler(Action::Yield);
+}
+
+#[no_mangle]
+pub extern "C" fn entry_point() {
+    // 指定されたエントリ関数を実行 <1>
+    let entry = current_worker()
+        .current
+        .borrow_mut()
+        .as_mut()
+        .unwrap()
+        .entry
+        .take()
+        .unwrap();
+
+    let reason = entry();
+
+    // 以降がスレッド終了時の後処理
+    // スタック領域はスケジューラが解放する
+    current_worker().current.borrow_mut().as_mut().unwrap().exit = reason;
+    switch_to_scheduler(Action::Exit);
+    panic!("entry_point"); // <4>
+}
+
+// 新たなランタイムで最初のスレッドを起動し、すべてのスレッドが終了するまで待機
+// 最初のスレッドの戻り値を返す
+pub fn spawn_from_main<F, R>(func: F, stack_size: usize) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    Runtime::new().run(func, stack_size)
+}
+
+// メッセージを送信
+#[rustfmt::skip]
+fn send(key: u64, msg: Message) { // <1>
+    {
+        let w = current_worker();
+        let mut state = w.rt().state.lock().unwrap();
+
+        // メッセージキューの最後尾に追加し、スレッドが受信待ちの場合に実行キューに移動
+        // 宛先のスレッドが終了している場合は、ロックを解放してから破棄
+        if let Err(msg) = w.rt().deliver(&mut state, w.index, key, msg) {
+            drop(state);
+            drop(msg);
+            return;
         }
     }
     schedule(); // <2>
 }
 
-pub fn recv() -> Option<u64> {
-    unsafe {
-        // スレッドIDを取得
-        let key = CONTEXTS.front().unwrap().id;
+// 実行中のスレッドに型Mのメッセージを送信するためのアドレス
+pub fn address<M: Send + 'static>() -> Address<M> {
+    Address::new(current_worker().current_id())
+}
 
-        // メッセージがすでにキューにある場合即座にリターン
-        if let Some(msg) = (*MESSAGES).pop_front(key) {
-            return Some(msg);
-        }
+// 型Mでpredを満たすメッセージのうち、最も古いものを受信
+// deadlineを過ぎても受信できない場合はNoneを返す
+// predはランタイムのロックを獲得した状態で呼び出されるため、ランタイムの関数を呼び出してはならない
+fn receive<M: Send + 'static>(pred: impl Fn(&M) -> bool, deadline: Option<Instant>) -> Option<M> {
+    loop {
+        {
+            let w = current_worker();
+            let key = w.current_id();
+            let mut state = w.rt().state.lock().unwrap();
 
-        // 実行可能なスレッドが他にいない場合はデッドロック
-        if CONTEXTS.len() == 1 {
-            panic!("deadlock");
+            // 該当するメッセージがキューにある場合即座にリターン
+            let msg = state
+                .mailboxes
+                .remove_first(key, |msg| msg.downcast_ref::<M>().is_some_and(&pred));
+            if let Some(msg) = msg {
+                state.deadlines.remove(&key);
+                drop(state);
+
+                // 待機せずに受信できた場合も切り替え点とする
+                preempt_point();
+                return Some(*msg.downcast::<M>().unwrap());
+            }
+
+            // 期限を過ぎていればタイムアウト。そうでなければ期限を登録
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    state.deadlines.remove(&key);
+                    return None;
+                }
+                w.rt().add_timer(&mut state, key, deadline);
+            }
         }
 
-        // 実行中のスレッドを受信待ち状態に移行
-        let mut ctx = CONTEXTS.pop_front().unwrap();
-        let regs = ctx.get_regs_mut();
-        (*WAITING).insert(key, ctx);
+        // 受信待ち状態に移行
+        // 他の型のメッセージの到着や期限切れなどでも起床するため、起床後にメッセージを再確認する
+        park_as(ThreadState::Receiving);
+    }
+}
 
-        // 次の実行可能なスレッドにコンテキストスイッチ
-        if set_context(regs) == 0 {
-            let next = CONTEXTS.front().unwrap();
-            switch_context((**next).get_regs());
+// durationが経過するまで、実行中のスレッドを待機させる
+// 待機中は他のスレッドが実行され、すべてのスレッドが待機している場合はOSスレッドが次の期限まで待機する
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    loop {
+        {
+            let w = current_worker();
+            let key = w.current_id();
+            let mut state = w.rt().state.lock().unwrap();
+            if Instant::now() >= deadline {
+                state.deadlines.remove(&key);
+                return;
+            }
+            w.rt().add_timer(&mut state, key, deadline);
         }
 
-        // 不要なスタックを削除
-        rm_unused_stack();
+        // メッセージの到着などでも起床するため、起床後に期限を再確認する
+        park_as(ThreadState::Sleeping);
+    }
+}
+
+// 型Mのメッセージを受信するまで待機
+// 他の型のメッセージはキューに残る
+pub fn recv<M: Send + 'static>() -> M {
+    receive(|_: &M| true, None).unwrap()
+}
+
+// 型Mでpredを満たすメッセージを受信するまで待機
+// 条件を満たさないメッセージはキューに残る
+pub fn recv_matching<M: Send + 'static>(pred: impl Fn(&M) -> bool) -> M {
+    receive(pred, None).unwrap()
+}
+
+// 型Mのメッセージを最大timeoutだけ待って受信
+// タイムアウトした場合はNoneを返す
+pub fn recv_timeout<M: Send + 'static>(timeout: Duration) -> Option<M> {
+    receive(|_: &M| true, Some(Instant::now() + timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STACK_SIZE: usize = 256 * 1024;
+
+    #[test]
+    fn spawn_and_join() {
+        let sum = Runtime::new().run(
+            || {
+                let threads: Vec<_> = (0..10u64)
+                    .map(|i| spawn(move || i * i, STACK_SIZE))
+                    .collect();
+                threads.into_iter().map(|t| t.join()).sum::<u64>()
+            },
+            STACK_SIZE,
+        );
+        assert_eq!(sum, 285);
+    }
+
+    #[test]
+    fn spawn_on_multiple_workers() {
+        let sum = Runtime::with_workers(4).run(
+            || {
+                let threads: Vec<_> = (0..10u64)
+                    .map(|i| {
+                        spawn(
+                            move || spawn(move || i + 1, STACK_SIZE).join() * 2,
+                            STACK_SIZE,
+                        )
+                    })
+                    .collect();
+                threads.into_iter().map(|t| t.join()).sum::<u64>()
+            },
+            STACK_SIZE,
+        );
+        assert_eq!(sum, 110);
+    }
+
+    #[test]
+    fn schedule_interleaves_threads() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let l = log.clone();
+        Runtime::new().run(
+            move || {
+                let l2 = l.clone();
+                let t = spawn(
+                    move || {
+                        for i in 0..3 {
+                            l2.lock().unwrap().push(('b', i));
+                            schedule();
+                        }
+                    },
+                    STACK_SIZE,
+                );
+                for i in 0..3 {
+                    l.lock().unwrap().push(('a', i));
+                    schedule();
+                }
+                t.join();
+            },
+            STACK_SIZE,
+        );
+        // spawnは生成したスレッドに切り替えるため、bから交互に実行される
+        assert_eq!(
+            *log.lock().unwrap(),
+            [('b', 0), ('a', 0), ('b', 1), ('a', 1), ('b', 2), ('a', 2)]
+        );
+    }
+
+    #[test]
+    fn preempt_point_switches_busy_thread() {
+        // ワーカが1つでも、ビジーループするスレッドは切り替え点で他のスレッドに切り替わる
+        let n = Runtime::new().preemptive(Duration::from_millis(1)).run(
+            || {
+                let flag = Arc::new(AtomicBool::new(false));
+                let f = flag.clone();
+                let spinner = spawn(
+                    move || {
+                        let mut n = 0u64;
+                        while !f.load(Ordering::Relaxed) {
+                            n += 1;
+                            preempt_point();
+                        }
+                        n
+                    },
+                    STACK_SIZE,
+                );
+                flag.store(true, Ordering::Relaxed);
+                spinner.join()
+            },
+            STACK_SIZE,
+        );
+        assert!(n > 0);
+    }
+
+    #[test]
+    fn busy_thread_without_preempt_point_is_reported() {
+        // 切り替え点を通過しないビジーループは切り替えられないが、検知される
+        let n = Runtime::new().preemptive(Duration::from_millis(1)).run(
+            || {
+                let spinner = spawn(
+                    || {
+                        let start = Instant::now();
+                        while start.elapsed() < Duration::from_millis(50) {
+                            std::hint::spin_loop();
+                        }
+                    },
+                    STACK_SIZE,
+                );
+                spinner.join();
+                starvations()
+            },
+            STACK_SIZE,
+        );
+        assert!(n > 0);
+    }
+
+    #[test]
+    fn send_and_recv() {
+        Runtime::new().run(
+            || {
+                let echo = spawn(
+                    || {
+                        let (reply, n) = recv::<(Address<u64>, u64)>();
+                        reply.send(n + 1);
+                    },
+                    STACK_SIZE,
+                );
+                echo.address().send((address::<u64>(), 41u64));
+                assert_eq!(recv::<u64>(), 42);
+                echo.join();
+
+                // 型や条件が一致しないメッセージはキューに残る
+                let me = address::<u64>();
+                me.send(1);
+                me.send(2);
+                address::<&'static str>().send("x");
+                assert_eq!(recv_matching(|n: &u64| *n > 1), 2);
+                assert_eq!(recv::<&'static str>(), "x");
+                assert_eq!(recv::<u64>(), 1);
+                assert_eq!(recv_timeout::<u64>(Duration::from_millis(10)), None);
+            },
+            STACK_SIZE,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    fn recv_without_sender_deadlocks() {
+        Runtime::new().run(recv::<u64>, STACK_SIZE);
+    }
+
+    #[test]
+    fn threads_report_states() {
+        Runtime::new().run(
+            || {
+                let sleeper = spawn(|| sleep(Duration::from_millis(10)), STACK_SIZE);
+                let receiver = spawn(recv::<u64>, 2 * STACK_SIZE);
+                let states: Vec<_> = threads()
+                    .into_iter()
+                    .map(|t| (t.id, t.state, t.stack_size))
+                    .collect();
+                assert_eq!(states.len(), 3);
+                assert!(states.contains(&(current_id(), ThreadState::Running, STACK_SIZE)));
+                assert!(states.contains(&(sleeper.id(), ThreadState::Sleeping, STACK_SIZE)));
+                assert!(states.contains(&(receiver.id(), ThreadState::Receiving, 2 * STACK_SIZE)));
+
+                receiver.address().send(1u64);
+                sleeper.join();
+                receiver.join();
+                assert_eq!(threads().len(), 1);
+                assert!(context_switches() >= 5);
+            },
+            STACK_SIZE,
+        );
+    }
+
+    #[test]
+    fn trace_records_every_thread() {
+        let rt = Runtime::with_workers(2).tracing();
+        rt.run(
+            || {
+                let threads: Vec<_> = (0..4).map(|_| spawn(schedule, STACK_SIZE)).collect();
+                for t in threads {
+                    t.join();
+                }
+            },
+            STACK_SIZE,
+        );
+        let json = rt.trace_json().unwrap();
+        assert_eq!(
+            json.matches("\"ph\":\"B\"").count(),
+            json.matches("\"ph\":\"E\"").count()
+        );
+        assert_eq!(json.matches("\"name\":\"spawn\"").count(), 5);
+        assert_eq!(json.matches("\"name\":\"exit\"").count(), 5);
+    }
+
+    // プロセスの仮想メモリサイズ(バイト)
+    fn vm_size() -> usize {
+        let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: usize = statm.split_whitespace().next().unwrap().parse().unwrap();
+        pages * PAGE_SIZE
+    }
+
+    // 正常終了・パニック・killで終了したスレッドのスタックが解放されることを確認
+    // 解放されなければ、64MiBのスタックを1000回確保するため仮想メモリが64GiB増加する
+    fn check_reclaimed(rt: Runtime) {
+        const LARGE_STACK: usize = 64 * 1024 * 1024;
+        rt.run(
+            || {
+                let before = vm_size();
+                for i in 0..1000 {
+                    match i % 3 {
+                        0 => spawn(|| (), LARGE_STACK).join(),
+                        1 => {
+                            spawn_monitor(|| panic!("reclaim"), LARGE_STACK);
+                            recv::<Exit>();
+                        }
+                        _ => {
+                            let h = spawn_monitor(recv::<()>, LARGE_STACK);
+                            kill(h.id());
+                            recv::<Exit>();
+                        }
+                    }
+                }
+                assert!(vm_size() < before + 4 * 1024 * 1024 * 1024);
+            },
+            STACK_SIZE,
+        );
+    }
+
+    #[test]
+    fn stacks_are_reclaimed() {
+        check_reclaimed(Runtime::new());
+    }
 
-        // 受信したメッセージを取得
-        (*MESSAGES).pop_front(key)
+    #[test]
+    fn lazy_stacks_are_reclaimed() {
+        check_reclaimed(Runtime::new().lazy_stacks());
     }
 }
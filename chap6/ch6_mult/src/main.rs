@@ -1,4 +1,15 @@
+mod arch;
 mod green;
+mod net;
+mod overflow;
+mod preempt;
+mod supervisor;
+mod sync;
+mod trace;
+
+// epoll、eventfd、timer_createなどLinux固有のAPIを利用するため、Linuxのみ対応
+#[cfg(not(target_os = "linux"))]
+compile_error!("only Linux is supported");
 
 fn mash() {
     green::spawn(ortega, 2 * 1024 * 1024);
@@ -23,21 +34,461 @@ fn gaia() {
     }
 }
 
+#[rustfmt::skip]
 fn producer() { // <1>
-    let id = green::spawn(consumer, 2 * 1024 * 1024);
+    let consumer = green::spawn(consumer, 2 * 1024 * 1024);
+    let addr = consumer.address::<u64>();
     for i in 0..10 {
-        green::send(id, i);
+        addr.send(i);
     }
+
+    // consumerの終了を待ち、受信したメッセージの合計を受け取る
+    let sum = consumer.join();
+    println!("sum = {}", sum);
 }
 
-fn consumer() { // <2>
+#[rustfmt::skip]
+fn consumer() -> u64 { // <2>
+    let mut sum = 0;
     for _ in 0..10 {
-        let msg = green::recv().unwrap();
+        let msg = green::recv::<u64>();
         println!("received: count = {}", msg);
+        sum += msg;
+    }
+    sum
+}
+
+// 加算器アクターへの命令
+enum Command {
+    Add(u64),
+    Get(green::Address<u64>), // 現在の値を指定されたアドレスに返信
+    Stop,
+}
+
+// 命令を受信して値を加算するアクター
+fn accumulator() -> u64 {
+    let mut total = 0;
+    loop {
+        match green::recv::<Command>() {
+            Command::Add(n) => total += n,
+            Command::Get(reply) => reply.send(total),
+            Command::Stop => return total,
+        }
+    }
+}
+
+// 型付きメッセージの実行例
+fn typed_messages() {
+    let acc = green::spawn(accumulator, 2 * 1024 * 1024);
+    let addr = acc.address::<Command>();
+    assert_eq!(addr.id(), acc.id());
+    for i in 1..=10 {
+        addr.send(Command::Add(i));
+    }
+    addr.send(Command::Get(green::address()));
+    println!("total = {}", green::recv::<u64>());
+
+    // 条件を満たすメッセージのみを受信。他のメッセージはキューに残る
+    let me = green::address::<String>();
+    me.send("hello".to_string());
+    me.send("world".to_string());
+    let msg = green::recv_matching(|s: &String| s.starts_with('w'));
+    println!("matched: {}", msg);
+    println!("remaining: {}", green::recv::<String>());
+
+    // 送信されないメッセージはタイムアウトする
+    let r = green::recv_timeout::<u64>(std::time::Duration::from_millis(100));
+    println!("timeout: {:?}", r);
+
+    addr.send(Command::Stop);
+    println!("final = {}", acc.join());
+}
+
+// スリープの実行例
+// 待機時間の短い順に起床する。すべてのスレッドがスリープ中は、OSスレッドが次の期限まで待機する
+fn sleeping() {
+    let threads: Vec<_> = [30, 10, 20]
+        .iter()
+        .map(|&ms| {
+            green::spawn(
+                move || {
+                    green::sleep(std::time::Duration::from_millis(ms));
+                    println!("woke up after {} ms", ms);
+                },
+                2 * 1024 * 1024,
+            )
+        })
+        .collect();
+    for t in threads {
+        t.join();
+    }
+}
+
+// リンク・モニタの実行例
+fn exit_signals() {
+    // パニックしたスレッドの終了理由を、モニタにより受信
+    let h = green::spawn_monitor(|| panic!("boom"), 2 * 1024 * 1024);
+    println!("monitor: {:?}", green::recv::<green::Exit>().reason);
+
+    // 終了済みのスレッドにリンクすると、即座に通知される
+    green::link(h.id());
+    println!("link: {:?}", green::recv::<green::Exit>().reason);
+
+    // killしたスレッドの終了を、リンクにより受信
+    let h = green::spawn_link(green::recv::<()>, 2 * 1024 * 1024);
+    green::kill(h.id());
+    println!("link: {:?}", green::recv::<green::Exit>().reason);
+
+    // 解除した場合は通知されない
+    let h = green::spawn(green::recv::<()>, 2 * 1024 * 1024);
+    green::link(h.id());
+    green::monitor(h.id());
+    green::unlink(h.id());
+    green::demonitor(h.id());
+    h.address::<()>().send(());
+    h.join();
+    let r = green::recv_timeout::<green::Exit>(std::time::Duration::from_millis(10));
+    println!("unlinked: {:?}", r);
+}
+
+// スーパーバイザの実行例
+fn supervision() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use supervisor::{Strategy, Supervisor};
+
+    // 2回パニックした後に成功する子スレッドを、パニックするたびに再起動
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let a = attempts.clone();
+    Supervisor::new(Strategy::OneForOne)
+        .child(
+            move || {
+                let n = a.fetch_add(1, Ordering::SeqCst);
+                if n < 2 {
+                    panic!("worker failed: attempt = {}", n);
+                }
+                println!("worker succeeded: attempt = {}", n);
+            },
+            2 * 1024 * 1024,
+        )
+        .spawn(2 * 1024 * 1024)
+        .join();
+
+    // 一方の子スレッドがパニックすると、もう一方も終了させて再起動
+    let starts = Arc::new(AtomicUsize::new(0));
+    let s = starts.clone();
+    let failed = Arc::new(AtomicUsize::new(0));
+    let f = failed.clone();
+    Supervisor::new(Strategy::OneForAll)
+        .child(
+            move || {
+                s.fetch_add(1, Ordering::SeqCst);
+                green::sleep(std::time::Duration::from_millis(50));
+            },
+            2 * 1024 * 1024,
+        )
+        .child(
+            move || {
+                if f.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("sibling failed");
+                }
+            },
+            2 * 1024 * 1024,
+        )
+        .spawn(2 * 1024 * 1024)
+        .join();
+    println!("one-for-all: starts = {}", starts.load(Ordering::SeqCst));
+
+    // 再起動回数の上限を超えると、スーパーバイザ自身が異常終了する
+    green::spawn_monitor(
+        || {
+            Supervisor::new(Strategy::OneForOne)
+                .max_restarts(2)
+                .child(|| panic!("always fails"), 2 * 1024 * 1024)
+                .spawn(2 * 1024 * 1024)
+                .join()
+        },
+        2 * 1024 * 1024,
+    );
+    println!("supervisor: {:?}", green::recv::<green::Exit>().reason);
+}
+
+// スタックを遅延確保する実行例
+// 8MiBのスタックを持つスレッドを10000個生成しても、物理メモリはアクセスしたページ分のみ消費する
+fn lazy_stacks() {
+    let rt = green::Runtime::new().lazy_stacks();
+    let sum = rt.run(
+        || {
+            let threads: Vec<_> = (0..10000)
+                .map(|_| green::spawn(green::recv::<u64>, 8 * 1024 * 1024))
+                .collect();
+            for (i, t) in threads.iter().enumerate() {
+                t.address().send(i as u64);
+            }
+            threads.into_iter().map(|t| t.join()).sum::<u64>()
+        },
+        2 * 1024 * 1024,
+    );
+    println!("lazy stacks: sum = {}", sum);
+}
+
+// スタックオーバーフローの実行例
+// ガードページへのアクセスを検知し、スレッドIDとスタックサイズを表示して異常終了する
+fn overflow() {
+    fn recurse(n: u64) -> u64 {
+        let buf = std::hint::black_box([0u8; 1024]);
+        if n == 0 {
+            0
+        } else {
+            recurse(n - 1) + buf[0] as u64
+        }
+    }
+    green::spawn_from_main(|| recurse(u64::MAX), 64 * 1024);
+}
+
+// epollによる非同期I/Oの実行例
+// I/Oがブロックする場合は呼び出し元のスレッドのみが待機し、他のスレッドは実行され続ける
+fn echo() {
+    use std::io::{Read, Write};
+
+    let rt = green::Runtime::with_workers(2);
+    rt.run(
+        || {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            // 接続ごとにスレッドを生成し、受信したデータをそのまま送り返す
+            let server = green::spawn(
+                move || {
+                    for _ in 0..4 {
+                        let (mut stream, _) = listener.accept().unwrap();
+                        green::spawn(
+                            move || {
+                                let mut buf = [0; 1024];
+                                loop {
+                                    let n = stream.read(&mut buf).unwrap();
+                                    if n == 0 {
+                                        break;
+                                    }
+                                    stream.write_all(&buf[..n]).unwrap();
+                                }
+                            },
+                            2 * 1024 * 1024,
+                        );
+                    }
+                },
+                2 * 1024 * 1024,
+            );
+
+            let clients: Vec<_> = (0..4)
+                .map(|i| {
+                    green::spawn(
+                        move || {
+                            let mut stream = net::TcpStream::connect(addr).unwrap();
+                            assert_eq!(stream.peer_addr().unwrap(), addr);
+                            let msg = format!("hello from client {}", i);
+                            stream.write_all(msg.as_bytes()).unwrap();
+                            stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+                            let mut reply = String::new();
+                            stream.read_to_string(&mut reply).unwrap();
+                            reply
+                        },
+                        2 * 1024 * 1024,
+                    )
+                })
+                .collect();
+
+            server.join();
+            for c in clients {
+                println!("echo: {}", c.join());
+            }
+        },
+        2 * 1024 * 1024,
+    );
+}
+
+// 同期プリミティブの実行例
+// ロックやチャネルで待機する場合は、OSスレッドではなく呼び出し元のスレッドのみが待機する
+fn sync_primitives() {
+    use std::sync::Arc;
+
+    let rt = green::Runtime::with_workers(2);
+    rt.run(
+        || {
+            // ロックを保持したまま切り替わっても、他のスレッドは解放されるまで待機する
+            let counter = Arc::new(sync::Mutex::new(0));
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    let c = counter.clone();
+                    green::spawn(
+                        move || {
+                            for _ in 0..100 {
+                                let mut n = c.lock();
+                                let v = *n;
+                                green::schedule();
+                                *n = v + 1;
+                            }
+                        },
+                        2 * 1024 * 1024,
+                    )
+                })
+                .collect();
+            for t in threads {
+                t.join();
+            }
+            println!("counter = {}", *counter.lock());
+
+            // 条件変数により、フラグが立つまで待機させてから一斉に起床
+            let ready = Arc::new((sync::Mutex::new(false), sync::Condvar::new()));
+            let waiters: Vec<_> = (0..3)
+                .map(|i| {
+                    let r = ready.clone();
+                    green::spawn(
+                        move || {
+                            let (lock, cond) = &*r;
+                            let mut started = lock.lock();
+                            while !*started {
+                                started = cond.wait(started);
+                            }
+                            i
+                        },
+                        2 * 1024 * 1024,
+                    )
+                })
+                .collect();
+            *ready.0.lock() = true;
+            ready.1.notify_all();
+            let ids: Vec<_> = waiters.into_iter().map(|t| t.join()).collect();
+            println!("started: {:?}", ids);
+
+            // 有限チャネル。キューが満杯の場合は送信側が待機する
+            let (tx, rx) = sync::channel(2);
+            for i in 0..3 {
+                let tx = tx.clone();
+                green::spawn(
+                    move || {
+                        for j in 0..5 {
+                            tx.send(i * 5 + j);
+                        }
+                    },
+                    2 * 1024 * 1024,
+                );
+            }
+            let sum: u64 = (0..15).map(|_| rx.recv()).sum();
+            println!("channel: sum = {}", sum);
+        },
+        2 * 1024 * 1024,
+    );
+}
+
+// スレッドの一覧とトレースの実行例
+// トレースはChrome trace形式のJSONで出力し、chrome://tracingやPerfettoで表示できる
+fn introspection() {
+    use std::time::Duration;
+
+    let rt = green::Runtime::new().tracing();
+    rt.run(
+        || {
+            let sleeper = green::spawn(|| green::sleep(Duration::from_millis(10)), 1024 * 1024);
+            let receiver = green::spawn(green::recv::<u64>, 512 * 1024);
+            for t in green::threads() {
+                println!(
+                    "thread {}: state = {:?}, stack = {} KiB, switches = {}",
+                    t.id,
+                    t.state,
+                    t.stack_size / 1024,
+                    t.switches
+                );
+            }
+            receiver.address().send(1u64);
+            sleeper.join();
+            receiver.join();
+            println!("context switches = {}", green::context_switches());
+        },
+        2 * 1024 * 1024,
+    );
+
+    let path = std::env::temp_dir().join("green_trace.json");
+    std::fs::write(&path, rt.trace_json().unwrap()).unwrap();
+    println!("trace: {}", path.display());
+}
+
+// n * 10からn * 10 + 9までを別々のスレッドで計算し、その合計を返す
+fn sum_actors(n: u64) -> u64 {
+    let threads: Vec<_> = (n * 10..n * 10 + 10)
+        .map(|i| green::spawn(move || i, 2 * 1024 * 1024))
+        .collect();
+    threads.into_iter().map(|t| t.join()).sum()
+}
+
+// CPU負荷の高い処理
+fn busy(n: u64) -> u64 {
+    let mut x = 0u64;
+    for i in 0..n {
+        x = std::hint::black_box(x.rotate_left(5) ^ i);
     }
+    x
+}
+
+// workers個のワーカで、CPU負荷の高いスレッドを8個並行に実行して時間を計測
+fn parallel(workers: usize) {
+    let rt = green::Runtime::with_workers(workers);
+    let start = std::time::Instant::now();
+    rt.run(
+        || {
+            let threads: Vec<_> = (0..8)
+                .map(|_| green::spawn(|| busy(100_000_000), 2 * 1024 * 1024))
+                .collect();
+            for t in threads {
+                t.join();
+            }
+        },
+        2 * 1024 * 1024,
+    );
+    println!("workers = {}: {:?}", workers, start.elapsed());
+}
+
+// プリエンプションの実行例
+// spinnerはフラグが立つまでビジーループし続けるが、
+// 切り替え点でタイマ割り込みによる切り替え要求に応じるため、フラグを立てるスレッドも実行される
+fn preemption() {
+    let rt = green::Runtime::new().preemptive(std::time::Duration::from_millis(10));
+    rt.run(
+        || {
+            let flag = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let f = flag.clone();
+            let spinner = green::spawn(
+                move || {
+                    let mut n = 0u64;
+                    while !f.load(std::sync::atomic::Ordering::Relaxed) {
+                        n += 1;
+                        green::preempt_point();
+                    }
+                    n
+                },
+                2 * 1024 * 1024,
+            );
+
+            flag.store(true, std::sync::atomic::Ordering::Relaxed);
+            let n = spinner.join();
+            println!(
+                "spinner stopped after {} loops (starvations = {})",
+                n,
+                green::starvations()
+            );
+        },
+        2 * 1024 * 1024,
+    );
 }
 
 fn main() {
+    // 引数にoverflowを指定した場合は、スタックオーバーフローの実行例のみ実行
+    if std::env::args().any(|arg| arg == "overflow") {
+        overflow();
+        return;
+    }
+
     // 6.2 協調的グリーンスレッドの実装の実行例
     green::spawn_from_main(gaia, 2 * 1024 * 1024);
 
@@ -45,4 +496,62 @@ fn main() {
 
     // 6.3 アクターモデルの実行例
     green::spawn_from_main(producer, 2 * 1024 * 1024); // <3>
-}
\ No newline at end of file
+
+    println!("--------------------");
+
+    // 型付きメッセージの実行例
+    green::spawn_from_main(typed_messages, 2 * 1024 * 1024);
+    green::spawn_from_main(sleeping, 2 * 1024 * 1024);
+
+    println!("--------------------");
+
+    // 異常終了の通知と、スーパーバイザによる再起動の実行例
+    green::spawn_from_main(exit_signals, 2 * 1024 * 1024);
+    green::spawn_from_main(supervision, 2 * 1024 * 1024);
+
+    println!("--------------------");
+
+    // OSスレッドごとに独立したランタイムを実行する例
+    let handles: Vec<_> = (0..2)
+        .map(|n| {
+            std::thread::spawn(move || {
+                green::spawn_from_main(move || sum_actors(n), 2 * 1024 * 1024)
+            })
+        })
+        .collect();
+    for h in handles {
+        println!("sum = {}", h.join().unwrap());
+    }
+
+    println!("--------------------");
+
+    // スタックの遅延確保の実行例
+    lazy_stacks();
+
+    println!("--------------------");
+
+    // 非同期I/Oの実行例
+    echo();
+
+    println!("--------------------");
+
+    // 同期プリミティブの実行例
+    sync_primitives();
+
+    println!("--------------------");
+
+    // スレッドの一覧とトレースの実行例
+    introspection();
+
+    println!("--------------------");
+
+    // M:Nスレッドの実行例
+    // ワーカを増やすと、CPU負荷の高いスレッドが複数のOSスレッドで並列に実行される
+    parallel(1);
+    parallel(4);
+
+    println!("--------------------");
+
+    // プリエンプションの実行例
+    preemption();
+}
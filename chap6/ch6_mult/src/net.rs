@@ -0,0 +1,187 @@
+use crate::green;
+use nix::errno::Errno;
+use nix::sys::socket::{connect, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use std::io::{self, Read, Write};
+use std::net::{self, Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+// nixのエラーをio::Errorに変換
+fn from_nix(err: nix::Error) -> io::Error {
+    match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    }
+}
+
+// 操作がブロックする場合は、fdが読み込み(write = falseの場合)、
+// または書き込み(write = trueの場合)可能になるまでグリーンスレッドを待機させて再試行
+fn retry<T>(fd: RawFd, write: bool, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if write {
+                    green::wait_writable(fd);
+                } else {
+                    green::wait_readable(fd);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            r => {
+                // 待機せずに完了した場合も切り替え点とする
+                green::preempt_point();
+                return r;
+            }
+        }
+    }
+}
+
+// グリーンスレッド用のTCPリスナ
+// acceptがブロックする場合は、OSスレッドではなく呼び出し元のグリーンスレッドのみが待機する
+pub struct TcpListener {
+    inner: net::TcpListener,
+}
+
+impl TcpListener {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let inner = net::TcpListener::bind(addr)?;
+        inner.set_nonblocking(true)?;
+        Ok(TcpListener { inner })
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (inner, addr) = retry(self.inner.as_raw_fd(), false, || self.inner.accept())?;
+        inner.set_nonblocking(true)?;
+        Ok((TcpStream { inner }, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+// グリーンスレッド用のTCPストリーム
+pub struct TcpStream {
+    inner: net::TcpStream,
+}
+
+impl TcpStream {
+    // 接続に成功するまで、解決したアドレスを順に試す
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let mut last = None;
+        for addr in addr.to_socket_addrs()? {
+            match Self::connect_addr(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last = Some(err),
+            }
+        }
+        Err(last.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+        }))
+    }
+
+    fn connect_addr(addr: SocketAddr) -> io::Result<Self> {
+        let family = match addr {
+            SocketAddr::V4(_) => AddressFamily::Inet,
+            SocketAddr::V6(_) => AddressFamily::Inet6,
+        };
+        let fd = socket(
+            family,
+            SockType::Stream,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .map_err(from_nix)?;
+
+        // エラー時にもソケットが閉じられるよう、すぐにTcpStreamで所有する
+        let inner = unsafe { net::TcpStream::from_raw_fd(fd) };
+
+        match connect(fd, &SockAddr::new_inet(InetAddr::from_std(&addr))) {
+            Ok(()) => return Ok(TcpStream { inner }),
+            Err(nix::Error::Sys(Errno::EINPROGRESS)) => (),
+            Err(err) => return Err(from_nix(err)),
+        }
+
+        // ノンブロッキングの接続は、書き込み可能になった時点で完了している
+        loop {
+            green::wait_writable(fd);
+            if let Some(err) = inner.take_error()? {
+                return Err(err);
+            }
+            match inner.peer_addr() {
+                Ok(_) => return Ok(TcpStream { inner }),
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry(inner.as_raw_fd(), false, || inner.read(buf))
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry(inner.as_raw_fd(), true, || inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::green::{sleep, spawn, Runtime};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const STACK_SIZE: usize = 256 * 1024;
+
+    #[test]
+    fn multiple_acceptors_on_one_listener() {
+        let sum = Runtime::new().run(
+            || {
+                let listener = Arc::new(TcpListener::bind("127.0.0.1:0").unwrap());
+                let addr = listener.local_addr().unwrap();
+                let acceptors: Vec<_> = (0..3)
+                    .map(|_| {
+                        let listener = listener.clone();
+                        spawn(
+                            move || {
+                                let (mut stream, _) = listener.accept().unwrap();
+                                let mut buf = [0; 1];
+                                stream.read_exact(&mut buf).unwrap();
+                                buf[0] as u64
+                            },
+                            STACK_SIZE,
+                        )
+                    })
+                    .collect();
+
+                // すべてのスレッドが同じfdでacceptを待機してから接続
+                sleep(Duration::from_millis(10));
+                for i in 1..=3 {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream.write_all(&[i]).unwrap();
+                }
+                acceptors.into_iter().map(|t| t.join()).sum::<u64>()
+            },
+            STACK_SIZE,
+        );
+        assert_eq!(sum, 6);
+    }
+}
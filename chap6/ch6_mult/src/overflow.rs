@@ -0,0 +1,155 @@
+use std::ffi::c_void;
+use std::fmt::Write;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::Once;
+
+// シグナルハンドラ用のスタックサイズ
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+// ハンドラ設定前のSIGSEGVのアクション
+// グリーンスレッドのガードページ以外へのアクセスは、元のハンドラに処理させる
+static mut OLD_ACTION: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+
+// シグナルハンドラ用のスタック
+// スタックオーバーフロー時はスタックに空きがないため、SIGSEGVのハンドラは別のスタックで実行する
+// 呼び出し元のOSスレッドにすでに設定されている場合は、それをそのまま利用する
+pub struct AltStack {
+    stack: *mut c_void, // 確保したスタック。既存のものを利用する場合はnull
+}
+
+impl AltStack {
+    pub fn new() -> Self {
+        install_handler();
+
+        unsafe {
+            let mut old: libc::stack_t = mem::zeroed();
+            libc::sigaltstack(ptr::null(), &mut old);
+            if old.ss_flags & libc::SS_DISABLE == 0 {
+                return AltStack {
+                    stack: ptr::null_mut(),
+                };
+            }
+
+            let stack = libc::mmap(
+                ptr::null_mut(),
+                ALT_STACK_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if stack == libc::MAP_FAILED {
+                panic!("mmap: {}", io::Error::last_os_error());
+            }
+
+            let ss = libc::stack_t {
+                ss_sp: stack,
+                ss_flags: 0,
+                ss_size: ALT_STACK_SIZE,
+            };
+            if libc::sigaltstack(&ss, ptr::null_mut()) != 0 {
+                panic!("sigaltstack: {}", io::Error::last_os_error());
+            }
+
+            AltStack { stack }
+        }
+    }
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        if self.stack.is_null() {
+            return;
+        }
+
+        unsafe {
+            let ss = libc::stack_t {
+                ss_sp: ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            };
+            libc::sigaltstack(&ss, ptr::null_mut());
+            libc::munmap(self.stack, ALT_STACK_SIZE);
+        }
+    }
+}
+
+// SIGSEGVのハンドラを設定。プロセスで1度だけ行う
+fn install_handler() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction =
+            on_fault as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) as usize;
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+
+        // ハンドラ実行中にタイマ割り込みで切り替わらないようにする
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaddset(&mut sa.sa_mask, libc::SIGALRM);
+
+        let old = ptr::addr_of_mut!(OLD_ACTION) as *mut libc::sigaction;
+        if libc::sigaction(libc::SIGSEGV, &sa, old) != 0 {
+            panic!("sigaction: {}", io::Error::last_os_error());
+        }
+    });
+}
+
+// 固定長のバッファへの書き込み
+// シグナルハンドラ内ではメモリを確保できないため、スタック上のバッファでメッセージを組み立てる
+pub struct Buf {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Buf {
+    pub fn new() -> Self {
+        Buf {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+
+    // 標準エラー出力に書き込む
+    pub fn print(&self) {
+        unsafe {
+            libc::write(
+                libc::STDERR_FILENO,
+                self.buf.as_ptr() as *const c_void,
+                self.len,
+            );
+        }
+    }
+}
+
+impl Write for Buf {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// SIGSEGVのハンドラ
+// 実行中のグリーンスレッドのガードページへのアクセスであれば、スレッドIDとスタックサイズを表示して終了
+// それ以外は元のハンドラに戻し、ハンドラから戻って同じ命令を再実行させる
+extern "C" fn on_fault(_sig: libc::c_int, info: *mut libc::siginfo_t, _uctx: *mut c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        if let Some((id, size)) = crate::green::guard_page_hit(addr) {
+            let mut buf = Buf::new();
+            let _ = writeln!(
+                buf,
+                "green thread {} has overflowed its stack (stack size = {} bytes)",
+                id, size
+            );
+            buf.print();
+            libc::abort();
+        }
+
+        let old = ptr::addr_of!(OLD_ACTION) as *const libc::sigaction;
+        libc::sigaction(libc::SIGSEGV, old, ptr::null_mut());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::green;
+use crate::overflow::Buf;
+use std::ffi::c_void;
+use std::fmt::Write;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+
+// 切り替え要求を確認しないまま、このタイムスライス数が経過したスレッドを報告する
+const STARVING_SLICES: usize = 10;
+
+thread_local! {
+    // タイマ割り込みで立てる、切り替え要求のフラグ
+    // ハンドラ内では切り替えず、グリーンスレッドが安全な切り替え点でフラグを確認して切り替える
+    // シグナルハンドラから書き込むため、const初期化でデストラクタのない型とする
+    static RESCHED: AtomicBool = const { AtomicBool::new(false) };
+
+    // 切り替え要求を確認しないまま経過したタイムスライス数
+    static PENDING: AtomicUsize = const { AtomicUsize::new(0) };
+}
+
+// 切り替え要求のフラグを取得して下ろす
+// グリーンスレッドはコンテキストスイッチの前後でOSスレッドを移動する可能性があるため、
+// スレッドローカル変数のアドレスがスイッチをまたいで再利用されないよう、インライン化しない
+#[inline(never)]
+pub fn take_resched() -> bool {
+    PENDING.with(|p| p.store(0, Ordering::Relaxed));
+    RESCHED.with(|r| r.swap(false, Ordering::Relaxed))
+}
+
+// プリエンプション用のインターバルタイマ
+// 生成したOSスレッドがCPU時間をslice消費するごとに、そのOSスレッドにSIGALRMを送る
+pub struct Timer {
+    id: libc::timer_t,
+}
+
+impl Timer {
+    pub fn start(slice: Duration) -> Self {
+        install_handler();
+
+        unsafe {
+            // 呼び出し元のOSスレッドのみにシグナルを送るよう設定
+            let mut sev: libc::sigevent = mem::zeroed();
+            sev.sigev_notify = libc::SIGEV_THREAD_ID;
+            sev.sigev_signo = libc::SIGALRM;
+            sev.sigev_notify_thread_id = libc::gettid();
+
+            let mut id = ptr::null_mut();
+            if libc::timer_create(libc::CLOCK_THREAD_CPUTIME_ID, &mut sev, &mut id) != 0 {
+                panic!("timer_create: {}", io::Error::last_os_error());
+            }
+
+            let ts = libc::timespec {
+                tv_sec: slice.as_secs() as libc::time_t,
+                tv_nsec: slice.subsec_nanos() as libc::c_long,
+            };
+            let spec = libc::itimerspec {
+                it_interval: ts,
+                it_value: ts,
+            };
+            if libc::timer_settime(id, 0, &spec, ptr::null_mut()) != 0 {
+                panic!("timer_settime: {}", io::Error::last_os_error());
+            }
+
+            Timer { id }
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::timer_delete(self.id) };
+    }
+}
+
+// SIGALRMのハンドラを設定。プロセスで1度だけ行う
+fn install_handler() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction =
+            on_timer as extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) as usize;
+        sa.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        if libc::sigaction(libc::SIGALRM, &sa, ptr::null_mut()) != 0 {
+            panic!("sigaction: {}", io::Error::last_os_error());
+        }
+    });
+}
+
+// タイマ割り込みのハンドラ
+// 割り込まれた処理はロックを保持している可能性があるため、ハンドラ内では切り替えず、
+// 切り替え要求のフラグを立てるのみとする
+// 切り替え点を通過しないスレッドは切り替えられないため、検知して一度だけ報告する
+extern "C" fn on_timer(_sig: libc::c_int, _info: *mut libc::siginfo_t, _uctx: *mut c_void) {
+    RESCHED.with(|r| r.store(true, Ordering::Relaxed));
+
+    let slices = PENDING.with(|p| p.fetch_add(1, Ordering::Relaxed)) + 1;
+    if slices != STARVING_SLICES {
+        return;
+    }
+    if let Some(id) = green::report_starving() {
+        let mut buf = Buf::new();
+        let _ = writeln!(
+            buf,
+            "green thread {} has not reached a preemption point for {} time slices",
+            id, slices
+        );
+        buf.print();
+    }
+}
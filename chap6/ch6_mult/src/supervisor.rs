@@ -0,0 +1,133 @@
+use crate::green::{self, Exit, ExitReason, JoinHandle};
+use std::sync::Arc;
+
+// 子スレッドの再起動戦略
+#[derive(Clone, Copy, Debug)]
+pub enum Strategy {
+    OneForOne, // 異常終了した子スレッドのみ再起動
+    OneForAll, // 1つでも異常終了した場合、実行中の子スレッドをすべて終了させてから再起動
+}
+
+// 子スレッドの生成関数。再起動のたびに呼び出される
+type Factory = Arc<dyn Fn() + Send + Sync>;
+
+struct Child {
+    factory: Factory,
+    stack_size: usize,
+    id: Option<u64>, // 実行中の子スレッドのID。正常終了した場合はNone
+}
+
+// 子スレッドをモニタし、パニックやkillで異常終了した場合に再起動するアクター
+// 正常終了した子スレッドは再起動せず、すべての子スレッドが正常終了するとスーパーバイザも終了する
+// 再起動回数が上限を超えた場合は、子スレッドをすべて終了させてからパニックし、
+// スーパーバイザをモニタしているスレッドに異常を伝える
+pub struct Supervisor {
+    strategy: Strategy,
+    max_restarts: usize, // 再起動回数の上限
+    children: Vec<Child>,
+}
+
+impl Supervisor {
+    pub fn new(strategy: Strategy) -> Self {
+        Supervisor {
+            strategy,
+            max_restarts: 3,
+            children: Vec::new(),
+        }
+    }
+
+    // 再起動回数の上限を設定
+    pub fn max_restarts(mut self, n: usize) -> Self {
+        self.max_restarts = n;
+        self
+    }
+
+    // 子スレッドを追加
+    pub fn child<F>(mut self, func: F, stack_size: usize) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.children.push(Child {
+            factory: Arc::new(func),
+            stack_size,
+            id: None,
+        });
+        self
+    }
+
+    // スーパーバイザをスレッドとして起動
+    pub fn spawn(self, stack_size: usize) -> JoinHandle<()> {
+        green::spawn(move || self.run(), stack_size)
+    }
+
+    fn run(mut self) {
+        for i in 0..self.children.len() {
+            self.start(i);
+        }
+
+        let mut restarts = 0;
+        while self.children.iter().any(|c| c.id.is_some()) {
+            let exit = green::recv::<Exit>();
+            let Some(i) = self.children.iter().position(|c| c.id == Some(exit.id)) else {
+                // 終了させた子スレッドの通知などは無視
+                continue;
+            };
+
+            self.children[i].id = None;
+            if exit.reason == ExitReason::Normal {
+                continue;
+            }
+
+            restarts += 1;
+            if restarts > self.max_restarts {
+                // 実行中の子スレッドはDropで終了させる
+                panic!("supervisor: too many restarts");
+            }
+
+            match self.strategy {
+                Strategy::OneForOne => self.start(i),
+                Strategy::OneForAll => {
+                    let mut stopped = self.stop_all();
+                    stopped.push(i);
+                    stopped.sort_unstable();
+                    for j in stopped {
+                        self.start(j);
+                    }
+                }
+            }
+        }
+    }
+
+    // i番目の子スレッドを起動
+    fn start(&mut self, i: usize) {
+        let child = &mut self.children[i];
+        let factory = child.factory.clone();
+        let handle = green::spawn_monitor(move || factory(), child.stack_size);
+        child.id = Some(handle.id());
+    }
+
+    // 実行中の子スレッドをすべて終了させ、終了を待つ
+    // 終了させた子スレッドの番号を返す
+    fn stop_all(&mut self) -> Vec<usize> {
+        let mut stopped = Vec::new();
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if let Some(id) = child.id.take() {
+                green::kill(id);
+                green::recv_matching(|e: &Exit| e.id == id);
+                stopped.push(i);
+            }
+        }
+        stopped
+    }
+}
+
+// スーパーバイザ自身がパニックやkillで終了する場合に、子スレッドを残さない
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            if let Some(id) = child.id.take() {
+                green::kill(id);
+            }
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::green;
+use std::cell::UnsafeCell;
+use std::collections::{LinkedList, VecDeque};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex as StdMutex, MutexGuard as StdMutexGuard};
+use std::thread;
+
+// 待機中のグリーンスレッドのIDの待ち行列
+#[derive(Default)]
+struct WaitQueue(VecDeque<u64>);
+
+impl WaitQueue {
+    fn push(&mut self, id: u64) {
+        if !self.0.contains(&id) {
+            self.0.push_back(id);
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> bool {
+        match self.0.iter().position(|&x| x == id) {
+            Some(pos) => {
+                self.0.remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 先頭のスレッドを起床
+    fn wake_one(&mut self) {
+        if let Some(id) = self.0.pop_front() {
+            green::unpark(id);
+        }
+    }
+
+    // すべてのスレッドを起床
+    fn wake_all(&mut self) {
+        for id in self.0.drain(..) {
+            green::unpark(id);
+        }
+    }
+}
+
+// 待ち行列に登録し、起床されるまで待機
+// 待ち行列を保護するロックsを獲得した状態で呼び出す
+// releaseは待ち行列への登録後、待機する前に呼び出される
+// 起床前にreleaseの処理により通知されても、parkが即座に戻るため通知を失わない
+fn wait_on<'a, S>(
+    lock: &'a StdMutex<S>,
+    mut s: StdMutexGuard<'a, S>,
+    queue: fn(&mut S) -> &mut WaitQueue,
+    release: impl FnOnce(),
+) {
+    let id = green::current_id();
+    queue(&mut s).push(id);
+    drop(s);
+    release();
+
+    let _dequeue = Dequeue { lock, queue, id };
+    green::park();
+}
+
+// 待機から戻る際に、待ち行列から削除
+// 通知以外で起床した場合は待ち行列に残っているため、ここで削除する
+// 通知を受けた後にkillにより巻き戻される場合は、通知を次のスレッドに引き継ぐ
+struct Dequeue<'a, S> {
+    lock: &'a StdMutex<S>,
+    queue: fn(&mut S) -> &mut WaitQueue,
+    id: u64,
+}
+
+impl<S> Drop for Dequeue<'_, S> {
+    fn drop(&mut self) {
+        let mut s = self.lock.lock().unwrap();
+        let queue = (self.queue)(&mut s);
+        if !queue.remove(self.id) && thread::panicking() {
+            queue.wake_one();
+        }
+    }
+}
+
+// グリーンスレッド用のミューテックス
+// ロックを獲得できない場合は、OSスレッドではなく呼び出し元のグリーンスレッドのみが待機する
+// すべてのスレッドが待機した場合は、ランタイムがデッドロックとして検知する
+pub struct Mutex<T> {
+    state: StdMutex<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+struct MutexState {
+    locked: bool,       // ロック獲得中の場合true
+    waiters: WaitQueue, // ロック獲得待ちのスレッド
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Self {
+        Mutex {
+            state: StdMutex::new(MutexState {
+                locked: false,
+                waiters: WaitQueue::default(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            let mut s = self.state.lock().unwrap();
+            if !s.locked {
+                s.locked = true;
+                return MutexGuard { mutex: self };
+            }
+
+            // 解放されるまで待機し、起床後に再度獲得を試みる
+            wait_on(&self.state, s, |s| &mut s.waiters, || ());
+        }
+    }
+
+    fn unlock(&self) {
+        let mut s = self.state.lock().unwrap();
+        s.locked = false;
+        s.waiters.wake_one();
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+// パニックやkillで巻き戻される場合も含め、ガードの破棄時にロックを解放
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// グリーンスレッド用の条件変数
+pub struct Condvar {
+    waiters: StdMutex<WaitQueue>,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Condvar {
+            waiters: StdMutex::new(WaitQueue::default()),
+        }
+    }
+
+    // ロックを解放して通知を待ち、起床後にロックを再獲得
+    // 通知以外で起床する場合もあるため、呼び出し元は条件を再確認すること
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let waiters = self.waiters.lock().unwrap();
+        wait_on(&self.waiters, waiters, |q| q, || drop(guard));
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.lock().unwrap().wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.lock().unwrap().wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// グリーンスレッド用のセマフォ
+// 3.8節のセマフォと同じく、Mutexと条件変数で実装する
+pub struct Semaphore {
+    mutex: Mutex<isize>,
+    cond: Condvar,
+    max: isize,
+}
+
+impl Semaphore {
+    pub fn new(max: isize) -> Self {
+        Semaphore {
+            mutex: Mutex::new(0),
+            cond: Condvar::new(),
+            max,
+        }
+    }
+
+    pub fn wait(&self) {
+        // カウントが最大値以上なら待機
+        let mut cnt = self.mutex.lock();
+        while *cnt >= self.max {
+            cnt = self.cond.wait(cnt);
+        }
+        *cnt += 1;
+    }
+
+    pub fn post(&self) {
+        // カウントをデクリメント
+        let mut cnt = self.mutex.lock();
+        *cnt -= 1;
+        if *cnt <= self.max {
+            self.cond.notify_one();
+        }
+    }
+}
+
+// グリーンスレッド用の有限チャネルの送信端
+// キューが満杯の場合は、受信されるまで送信側のスレッドが待機する
+pub struct Sender<T> {
+    sem: Arc<Semaphore>,            // 有限性を実現するセマフォ
+    buf: Arc<Mutex<LinkedList<T>>>, // キュー
+    cond: Arc<Condvar>,             // 読み込み側の条件変数
+}
+
+// 複数のスレッドから送信できるよう、Tによらず複製できる
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            sem: self.sem.clone(),
+            buf: self.buf.clone(),
+            cond: self.cond.clone(),
+        }
+    }
+}
+
+impl<T: Send> Sender<T> {
+    pub fn send(&self, data: T) {
+        self.sem.wait(); // キューの最大値に到達したら待機
+        let mut buf = self.buf.lock();
+        buf.push_back(data);
+        self.cond.notify_one(); // 読み込み側へ通知
+    }
+}
+
+// グリーンスレッド用の有限チャネルの受信端
+pub struct Receiver<T> {
+    sem: Arc<Semaphore>,
+    buf: Arc<Mutex<LinkedList<T>>>,
+    cond: Arc<Condvar>,
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&self) -> T {
+        let mut buf = self.buf.lock();
+        loop {
+            if let Some(data) = buf.pop_front() {
+                self.sem.post();
+                return data;
+            }
+            // 空の場合待機
+            buf = self.cond.wait(buf);
+        }
+    }
+}
+
+// 最大max個のデータをキューに保持できるチャネルを生成
+pub fn channel<T>(max: isize) -> (Sender<T>, Receiver<T>) {
+    assert!(max > 0);
+    let sem = Arc::new(Semaphore::new(max));
+    let buf = Arc::new(Mutex::new(LinkedList::new()));
+    let cond = Arc::new(Condvar::new());
+    let tx = Sender {
+        sem: sem.clone(),
+        buf: buf.clone(),
+        cond: cond.clone(),
+    };
+    let rx = Receiver { sem, buf, cond };
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::green::{self, Runtime};
+
+    const STACK_SIZE: usize = 256 * 1024;
+
+    #[test]
+    fn mutex_excludes_threads() {
+        let n = Runtime::with_workers(2).run(
+            || {
+                let counter = Arc::new(Mutex::new(0));
+                let threads: Vec<_> = (0..8)
+                    .map(|_| {
+                        let c = counter.clone();
+                        green::spawn(
+                            move || {
+                                for _ in 0..100 {
+                                    let mut n = c.lock();
+                                    let v = *n;
+                                    green::schedule();
+                                    *n = v + 1;
+                                }
+                            },
+                            STACK_SIZE,
+                        )
+                    })
+                    .collect();
+                for t in threads {
+                    t.join();
+                }
+                let n = *counter.lock();
+                n
+            },
+            STACK_SIZE,
+        );
+        assert_eq!(n, 800);
+    }
+
+    #[test]
+    fn channel_is_bounded() {
+        Runtime::new().run(
+            || {
+                let (tx, rx) = channel(2);
+                let sent = Arc::new(Mutex::new(0));
+                let s = sent.clone();
+                let producer = green::spawn(
+                    move || {
+                        for i in 0..5 {
+                            tx.send(i);
+                            *s.lock() += 1;
+                        }
+                    },
+                    STACK_SIZE,
+                );
+
+                // 受信しない間は、キューの最大値までしか送信できない
+                green::sleep(std::time::Duration::from_millis(10));
+                assert_eq!(*sent.lock(), 2);
+                assert_eq!(
+                    (0..5).map(|_| rx.recv()).collect::<Vec<_>>(),
+                    [0, 1, 2, 3, 4]
+                );
+                producer.join();
+            },
+            STACK_SIZE,
+        );
+    }
+
+    #[test]
+    fn killed_waiter_passes_on_wakeup() {
+        Runtime::new().run(
+            || {
+                let lock = Arc::new(Mutex::new(()));
+                let guard = lock.lock();
+                let waiters: Vec<_> = (0..2)
+                    .map(|_| {
+                        let l = lock.clone();
+                        green::spawn_monitor(move || drop(l.lock()), STACK_SIZE)
+                    })
+                    .collect();
+
+                // 先頭の待機スレッドを起床させた直後にkillしても、次の待機スレッドがロックを獲得する
+                drop(guard);
+                green::kill(waiters[0].id());
+                for _ in 0..2 {
+                    green::recv::<green::Exit>();
+                }
+            },
+            STACK_SIZE,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    fn lock_order_inversion_deadlocks() {
+        Runtime::new().run(
+            || {
+                let a = Arc::new(Mutex::new(()));
+                let b = Arc::new(Mutex::new(()));
+                let (a2, b2) = (a.clone(), b.clone());
+                let t = green::spawn(
+                    move || {
+                        let _b = b2.lock();
+                        green::schedule();
+                        let _a = a2.lock();
+                    },
+                    STACK_SIZE,
+                );
+                let _a = a.lock();
+                green::schedule();
+                let _b = b.lock();
+                t.join();
+            },
+            STACK_SIZE,
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::green::ThreadState;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// トレースするイベントの種類
+#[derive(Clone, Copy)]
+pub enum EventKind {
+    Spawn,              // スレッドを生成
+    Begin,              // スレッドに切り替えて実行を開始
+    End,                // スレッドがスケジューラに制御を戻した
+    Block(ThreadState), // 待機状態に移行。待機の理由を保持する
+    Wake,               // 待機状態から実行キューに移動
+    Exit(&'static str), // 終了。終了理由を保持する
+}
+
+struct Event {
+    ts: Duration,  // トレース開始からの経過時間
+    worker: usize, // イベントが発生したワーカ
+    id: u64,       // 対象のスレッドID
+    kind: EventKind,
+}
+
+// スケジューラのイベントの記録
+pub struct Trace {
+    start: Instant,
+    events: Mutex<Vec<Event>>,
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Trace {
+            start: Instant::now(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record(&self, worker: usize, id: u64, kind: EventKind) {
+        let ts = self.start.elapsed();
+        self.events.lock().unwrap().push(Event {
+            ts,
+            worker,
+            id,
+            kind,
+        });
+    }
+
+    // Chrome trace形式のJSONに変換
+    // chrome://tracingやPerfettoで読み込むと、ワーカごとにスレッドの実行区間が表示される
+    // 実行区間はB/Eイベント、それ以外は瞬間イベントで表す
+    // スレッドIDはJavaScriptの数値で正確に表せないため、文字列として出力する
+    pub fn to_json(&self) -> String {
+        let events = self.events.lock().unwrap();
+        let mut json = String::from("[\n");
+        for (i, e) in events.iter().enumerate() {
+            let ts = e.ts.as_nanos() as f64 / 1000.0;
+            let (name, ph, args) = match e.kind {
+                EventKind::Begin => (format!("thread {}", e.id), "B", String::new()),
+                EventKind::End => (format!("thread {}", e.id), "E", String::new()),
+                EventKind::Spawn => ("spawn".to_string(), "i", String::new()),
+                EventKind::Wake => ("wake".to_string(), "i", String::new()),
+                EventKind::Block(state) => (
+                    "block".to_string(),
+                    "i",
+                    format!(",\"reason\":\"{:?}\"", state),
+                ),
+                EventKind::Exit(reason) => (
+                    "exit".to_string(),
+                    "i",
+                    format!(",\"reason\":\"{}\"", reason),
+                ),
+            };
+            let _ = write!(
+                json,
+                "  {{\"name\":\"{}\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":1,\"tid\":{}",
+                name, ph, ts, e.worker
+            );
+            if ph == "i" {
+                let _ = write!(
+                    json,
+                    ",\"s\":\"t\",\"args\":{{\"id\":\"{}\"{}}}",
+                    e.id, args
+                );
+            }
+            json.push('}');
+            if i + 1 < events.len() {
+                json.push(',');
+            }
+            json.push('\n');
+        }
+        json.push_str("]\n");
+        json
+    }
+}
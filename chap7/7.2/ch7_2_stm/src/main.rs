@@ -1,59 +1,56 @@
 use std::sync::Arc;
 use std::{thread, time};
 
+// tl2はライブラリとして利用するため、デモで使わないAPIの警告を抑制
+#[allow(dead_code)]
 mod tl2;
 
+// トランザクショナルなコレクション。デモでは未使用
+#[allow(dead_code)]
+mod tcollections;
+
 // 哲学者の数
 const NUM_PHILOSOPHERS: usize = 8;
 
-fn philosopher(stm: Arc<tl2::STM>, n: usize) { // <1>
+fn philosopher(stm: Arc<tl2::STM>, chopsticks: Vec<tl2::TVar<u8>>, n: usize) { // <1>
     // 左と右の箸用のメモリ <2>
-    let left = 8 * n;
-    let right = 8 * ((n + 1) % NUM_PHILOSOPHERS);
+    let left = chopsticks[n];
+    let right = chopsticks[(n + 1) % NUM_PHILOSOPHERS];
 
     for _ in 0..500000 {
         // 箸を取り上げる
-        while !stm
-            .write_transaction(|tr| {
-                let mut f1 = load!(tr, left);  // 左の箸 <3>
-                let mut f2 = load!(tr, right); // 右の箸
-                if f1[0] == 0 && f2[0] == 0 { // <4>
-                    // 両方空いていれば1に設定
-                    f1[0] = 1;
-                    f2[0] = 1;
-                    store!(tr, left, f1);
-                    store!(tr, right, f2);
-                    tl2::STMResult::Ok(true)
-                } else {
-                    // 両方取れない場合取得失敗
-                    tl2::STMResult::Ok(false)
-                }
-            })
-            .unwrap()
-        { }
+        stm.write_transaction(|tr| {
+            let f1 = load_var!(tr, left);  // 左の箸 <3>
+            let f2 = load_var!(tr, right); // 右の箸
+            if f1 != 0 || f2 != 0 { // <4>
+                // どちらかが取られている場合は、箸の状態が変わるまで待機
+                return tl2::STMResult::Retry;
+            }
+
+            // 両方空いていれば1に設定
+            store_var!(tr, left, 1);
+            store_var!(tr, right, 1);
+            tl2::STMResult::Ok(())
+        });
 
         // 箸を置く <5>
         stm.write_transaction(|tr| {
-            let mut f1 = load!(tr, left);
-            let mut f2 = load!(tr, right);
-            f1[0] = 0;
-            f2[0] = 0;
-            store!(tr, left, f1);
-            store!(tr, right, f2);
+            store_var!(tr, left, 0);
+            store_var!(tr, right, 0);
             tl2::STMResult::Ok(())
         });
     }
 }
 
 // 観測者
-fn observer(stm: Arc<tl2::STM>) {
+fn observer(stm: Arc<tl2::STM>, vars: Vec<tl2::TVar<u8>>) {
     for _ in 0..10000 {
         // 箸の現在の状態を取得 <1>
         let chopsticks = stm
             .read_transaction(|tr| {
                 let mut v = [0; NUM_PHILOSOPHERS];
-                for i in 0..NUM_PHILOSOPHERS {
-                    v[i] = load!(tr, 8 * i)[0];
+                for (c, var) in v.iter_mut().zip(vars.iter()) {
+                    *c = load_var!(tr, var);
                 }
 
                 tl2::STMResult::Ok(v)
@@ -63,12 +60,7 @@ fn observer(stm: Arc<tl2::STM>) {
         println!("{:?}", chopsticks);
 
         // 取り上げられている箸が奇数の場合不正 <2>
-        let mut n = 0;
-        for c in &chopsticks {
-            if *c == 1 {
-                n += 1;
-            }
-        }
+        let n = chopsticks.iter().filter(|c| **c != 0).count();
 
         if n & 1 != 0 {
             panic!("inconsistent");
@@ -84,19 +76,29 @@ fn main() {
     let stm = Arc::new(tl2::STM::new());
     let mut v = Vec::new();
 
+    // 箸用のTVarを生成。0なら空いている
+    let chopsticks: Vec<_> = (0..NUM_PHILOSOPHERS)
+        .map(|_| stm.new_tvar(0))
+        .collect();
+
     // 哲学者のスレッド生成
     for i in 0..NUM_PHILOSOPHERS {
         let s = stm.clone();
-        let th = std::thread::spawn(move || philosopher(s, i));
+        let c = chopsticks.clone();
+        let th = std::thread::spawn(move || philosopher(s, c, i));
         v.push(th);
     }
 
     // 観測者のスレッド生成
-    let obs = std::thread::spawn(move || observer(stm));
+    let s = stm.clone();
+    let obs = std::thread::spawn(move || observer(s, chopsticks));
 
     for th in v {
         th.join().unwrap();
     }
 
     obs.join().unwrap();
+
+    // コミット数と中断数を表示
+    println!("{:?}", stm.stats());
 }
\ No newline at end of file
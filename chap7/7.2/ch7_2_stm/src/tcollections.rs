@@ -0,0 +1,429 @@
+use crate::tl2::{self, Pod, STMResult, TVar, WriteTrans, DEFAULT_STRIPE_SIZE, STM};
+use crate::{load_var, stm_try, store_var};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+// 固定長の配列
+// 各要素はTVarで、要素ごとに独立して競合が検知される
+pub struct TArray<T, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    head: TVar<T, STRIPE_SIZE>, // 先頭の要素
+    len: usize,                 // 要素数
+}
+
+// TArrayはアドレスと要素数のみを保持するためTによらずコピー可能
+impl<T, const STRIPE_SIZE: usize> Clone for TArray<T, STRIPE_SIZE> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const STRIPE_SIZE: usize> Copy for TArray<T, STRIPE_SIZE> {}
+
+impl<T: Pod, const STRIPE_SIZE: usize> TArray<T, STRIPE_SIZE> {
+    // 要素数lenの配列を生成し、すべての要素をvalで初期化
+    pub fn new(stm: &STM<STRIPE_SIZE>, len: usize, val: T) -> Self {
+        stm.write_transaction(|tr| match Self::alloc(tr, len, val) {
+            Some(arr) => STMResult::Ok(arr),
+            None => STMResult::Abort,
+        })
+        .expect("out of memory")
+    }
+
+    // トランザクション中に配列を割り当て
+    // メモリ不足の場合はNoneを返す
+    pub fn alloc(tr: &mut WriteTrans<STRIPE_SIZE>, len: usize, val: T) -> Option<Self> {
+        Some(TArray {
+            head: tr.alloc_array(len, val)?,
+            len,
+        })
+    }
+
+    // 配列を解放
+    pub fn free(self, tr: &mut WriteTrans<STRIPE_SIZE>) {
+        tr.free_var(self.head);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // i番目の要素のTVar
+    fn var(&self, i: usize) -> TVar<T, STRIPE_SIZE> {
+        assert!(i < self.len, "index out of bounds: {} >= {}", i, self.len);
+        self.head.offset(i)
+    }
+
+    // i番目の要素を読み込み
+    pub fn get(&self, tr: &mut WriteTrans<STRIPE_SIZE>, i: usize) -> STMResult<T> {
+        STMResult::Ok(load_var!(tr, self.var(i)))
+    }
+
+    // i番目の要素に書き込み
+    pub fn set(&self, tr: &mut WriteTrans<STRIPE_SIZE>, i: usize, val: T) {
+        store_var!(tr, self.var(i), val);
+    }
+}
+
+// 容量固定のFIFOキュー
+// 先頭と末尾の位置を別のTVarで管理し、
+// 空でも満杯でもない場合のpushとpopの競合を書き込み対象の要素のみに抑える
+pub struct TQueue<T, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    buf: TArray<T, STRIPE_SIZE>,   // リングバッファ
+    head: TVar<usize, STRIPE_SIZE>, // 取り出した要素の累計
+    tail: TVar<usize, STRIPE_SIZE>, // 追加した要素の累計
+}
+
+impl<T, const STRIPE_SIZE: usize> Clone for TQueue<T, STRIPE_SIZE> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const STRIPE_SIZE: usize> Copy for TQueue<T, STRIPE_SIZE> {}
+
+impl<T: Pod + Default, const STRIPE_SIZE: usize> TQueue<T, STRIPE_SIZE> {
+    // 容量capのキューを生成
+    pub fn new(stm: &STM<STRIPE_SIZE>, cap: usize) -> Self {
+        assert!(cap > 0, "capacity must be positive");
+        stm.write_transaction(|tr| {
+            let buf = match TArray::alloc(tr, cap, T::default()) {
+                Some(buf) => buf,
+                None => return STMResult::Abort,
+            };
+            match (tr.alloc_var(0), tr.alloc_var(0)) {
+                (Some(head), Some(tail)) => STMResult::Ok(TQueue { buf, head, tail }),
+                _ => STMResult::Abort,
+            }
+        })
+        .expect("out of memory")
+    }
+}
+
+impl<T: Pod, const STRIPE_SIZE: usize> TQueue<T, STRIPE_SIZE> {
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    // 現在の要素数
+    pub fn len(&self, tr: &mut WriteTrans<STRIPE_SIZE>) -> STMResult<usize> {
+        let head = load_var!(tr, self.head);
+        let tail = load_var!(tr, self.tail);
+        STMResult::Ok(tail - head)
+    }
+
+    // 末尾に追加。満杯の場合はfalseを返す
+    pub fn try_push(&self, tr: &mut WriteTrans<STRIPE_SIZE>, val: T) -> STMResult<bool> {
+        let head = load_var!(tr, self.head);
+        let tail = load_var!(tr, self.tail);
+        if tail - head == self.capacity() {
+            return STMResult::Ok(false);
+        }
+
+        self.buf.set(tr, tail % self.capacity(), val);
+        store_var!(tr, self.tail, tail + 1);
+        STMResult::Ok(true)
+    }
+
+    // 先頭から取り出し。空の場合はNoneを返す
+    pub fn try_pop(&self, tr: &mut WriteTrans<STRIPE_SIZE>) -> STMResult<Option<T>> {
+        let head = load_var!(tr, self.head);
+        let tail = load_var!(tr, self.tail);
+        if head == tail {
+            return STMResult::Ok(None);
+        }
+
+        let val = stm_try!(self.buf.get(tr, head % self.capacity()));
+        store_var!(tr, self.head, head + 1);
+        STMResult::Ok(Some(val))
+    }
+
+    // 末尾に追加。満杯の場合は空きができるまでretryで待機
+    pub fn push(&self, tr: &mut WriteTrans<STRIPE_SIZE>, val: T) -> STMResult<()> {
+        if stm_try!(self.try_push(tr, val)) {
+            STMResult::Ok(())
+        } else {
+            STMResult::Retry
+        }
+    }
+
+    // 先頭から取り出し。空の場合は要素が追加されるまでretryで待機
+    pub fn pop(&self, tr: &mut WriteTrans<STRIPE_SIZE>) -> STMResult<T> {
+        match stm_try!(self.try_pop(tr)) {
+            Some(val) => STMResult::Ok(val),
+            None => STMResult::Retry,
+        }
+    }
+}
+
+// ハッシュマップのエントリへのリンク。nullなら末尾
+// エントリはキー、値、次のエントリへのリンクを連続したストライプに格納し、
+// キーのTVarでエントリを表す
+// 構造体として格納するとパディングを含み得るため、フィールドごとにTVarを分ける
+type Link<K, const STRIPE_SIZE: usize> = TVar<K, STRIPE_SIZE>;
+
+// チェイン法によるハッシュマップ
+// バケット数は固定で、異なるバケットへの操作は互いに競合しない
+pub struct TMap<K, V, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    buckets: TArray<Link<K, STRIPE_SIZE>, STRIPE_SIZE>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V, const STRIPE_SIZE: usize> Clone for TMap<K, V, STRIPE_SIZE> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V, const STRIPE_SIZE: usize> Copy for TMap<K, V, STRIPE_SIZE> {}
+
+impl<K, V, const STRIPE_SIZE: usize> TMap<K, V, STRIPE_SIZE>
+where
+    K: Pod + Hash + Eq,
+    V: Pod,
+{
+    // バケット数を指定してハッシュマップを生成
+    pub fn new(stm: &STM<STRIPE_SIZE>, num_buckets: usize) -> Self {
+        assert!(num_buckets > 0, "number of buckets must be positive");
+        TMap {
+            buckets: TArray::new(stm, num_buckets, TVar::null()),
+            _marker: PhantomData,
+        }
+    }
+
+    // エントリの値のTVar
+    fn val(node: Link<K, STRIPE_SIZE>) -> TVar<V, STRIPE_SIZE> {
+        node.field(TVar::<K, STRIPE_SIZE>::NUM_STRIPES)
+    }
+
+    // エントリの次のエントリへのリンクのTVar
+    fn next(node: Link<K, STRIPE_SIZE>) -> TVar<Link<K, STRIPE_SIZE>, STRIPE_SIZE> {
+        node.field(TVar::<K, STRIPE_SIZE>::NUM_STRIPES + TVar::<V, STRIPE_SIZE>::NUM_STRIPES)
+    }
+
+    // keyが属するバケットの番号
+    fn bucket(&self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.buckets.len() as u64) as usize
+    }
+
+    // keyのエントリと、その直前のエントリを探索
+    // 直前のエントリがnullの場合はバケットの先頭
+    fn find(
+        &self,
+        tr: &mut WriteTrans<STRIPE_SIZE>,
+        key: &K,
+    ) -> STMResult<(Link<K, STRIPE_SIZE>, Link<K, STRIPE_SIZE>)> {
+        let mut prev = TVar::null();
+        let mut cur = stm_try!(self.buckets.get(tr, self.bucket(key)));
+        while !cur.is_null() {
+            if load_var!(tr, cur) == *key {
+                break;
+            }
+            prev = cur;
+            cur = load_var!(tr, Self::next(cur));
+        }
+        STMResult::Ok((prev, cur))
+    }
+
+    // keyに対応する値を取得
+    pub fn get(&self, tr: &mut WriteTrans<STRIPE_SIZE>, key: &K) -> STMResult<Option<V>> {
+        let (_, node) = stm_try!(self.find(tr, key));
+        if node.is_null() {
+            return STMResult::Ok(None);
+        }
+        STMResult::Ok(Some(load_var!(tr, Self::val(node))))
+    }
+
+    pub fn contains_key(&self, tr: &mut WriteTrans<STRIPE_SIZE>, key: &K) -> STMResult<bool> {
+        STMResult::Ok(!stm_try!(self.find(tr, key)).1.is_null())
+    }
+
+    // keyに対応する値を設定し、以前の値を返す
+    // メモリ不足の場合はAbortを返す
+    pub fn insert(&self, tr: &mut WriteTrans<STRIPE_SIZE>, key: K, val: V) -> STMResult<Option<V>> {
+        let (_, node) = stm_try!(self.find(tr, &key));
+        if !node.is_null() {
+            // 既存のエントリを更新
+            let old = load_var!(tr, Self::val(node));
+            store_var!(tr, Self::val(node), val);
+            return STMResult::Ok(Some(old));
+        }
+
+        // バケットの先頭に追加
+        let idx = self.bucket(&key);
+        let next = stm_try!(self.buckets.get(tr, idx));
+        let n = TVar::<K, STRIPE_SIZE>::NUM_STRIPES
+            + TVar::<V, STRIPE_SIZE>::NUM_STRIPES
+            + TVar::<Link<K, STRIPE_SIZE>, STRIPE_SIZE>::NUM_STRIPES;
+        let node = match tr.alloc_stripes(n) {
+            Some(node) => node,
+            None => return STMResult::Abort,
+        };
+        store_var!(tr, node, key);
+        store_var!(tr, Self::val(node), val);
+        store_var!(tr, Self::next(node), next);
+        self.buckets.set(tr, idx, node);
+        STMResult::Ok(None)
+    }
+
+    // keyのエントリを削除し、その値を返す
+    pub fn remove(&self, tr: &mut WriteTrans<STRIPE_SIZE>, key: &K) -> STMResult<Option<V>> {
+        let (prev, node) = stm_try!(self.find(tr, key));
+        if node.is_null() {
+            return STMResult::Ok(None);
+        }
+
+        // 連結リストから外して解放
+        let val = load_var!(tr, Self::val(node));
+        let next = load_var!(tr, Self::next(node));
+        if prev.is_null() {
+            self.buckets.set(tr, self.bucket(key), next);
+        } else {
+            store_var!(tr, Self::next(prev), next);
+        }
+        tr.free_var(node);
+        STMResult::Ok(Some(val))
+    }
+
+    // エントリ数
+    // すべてのバケットを走査するため、他のすべての更新と競合する
+    pub fn len(&self, tr: &mut WriteTrans<STRIPE_SIZE>) -> STMResult<usize> {
+        let mut n = 0;
+        for i in 0..self.buckets.len() {
+            let mut cur = stm_try!(self.buckets.get(tr, i));
+            while !cur.is_null() {
+                n += 1;
+                cur = load_var!(tr, Self::next(cur));
+            }
+        }
+        STMResult::Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn move_from_queue_to_map_is_atomic() {
+        const N: u64 = 200;
+        let stm = Arc::new(STM::new());
+        let queue = TQueue::<u64>::new(&stm, N as usize);
+        let map = TMap::<u64, u64>::new(&stm, 16);
+        for i in 0..N {
+            stm.write_transaction(|tr| queue.push(tr, i));
+        }
+
+        // キューから取り出してハッシュマップへ移す
+        let movers: Vec<_> = (0..4)
+            .map(|_| {
+                let stm = stm.clone();
+                thread::spawn(move || loop {
+                    let moved = stm.write_transaction(|tr| match stm_try!(queue.try_pop(tr)) {
+                        Some(key) => {
+                            stm_try!(map.insert(tr, key, key * 2));
+                            STMResult::Ok(true)
+                        }
+                        None => STMResult::Ok(false),
+                    });
+                    if moved != Some(true) {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        // 移動の途中でも、要素は必ずどちらか一方にのみ存在する
+        let done = Arc::new(AtomicBool::new(false));
+        let observer = {
+            let stm = stm.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let total = stm.write_transaction(|tr| {
+                        let q = stm_try!(queue.len(tr));
+                        let m = stm_try!(map.len(tr));
+                        STMResult::Ok(q + m)
+                    });
+                    assert_eq!(total, Some(N as usize));
+                }
+            })
+        };
+
+        for th in movers {
+            th.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        observer.join().unwrap();
+
+        let vals = stm.write_transaction(|tr| {
+            let mut vals = Vec::new();
+            for i in 0..N {
+                vals.push(stm_try!(map.get(tr, &i)));
+            }
+            STMResult::Ok(vals)
+        });
+        let expected: Vec<_> = (0..N).map(|i| Some(i * 2)).collect();
+        assert_eq!(vals, Some(expected));
+    }
+
+    #[test]
+    fn concurrent_remove_of_same_key() {
+        const N: u64 = 64;
+        let stm = Arc::new(STM::new());
+        let map = TMap::<u64, u64>::new(&stm, 4);
+
+        for _ in 0..100 {
+            for i in 0..N {
+                stm.write_transaction(|tr| map.insert(tr, i, i));
+            }
+
+            // すべてのスレッドが同じキーを削除し、各キーは一度だけ削除される
+            let removed = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let stm = stm.clone();
+                    let removed = removed.clone();
+                    thread::spawn(move || {
+                        for i in 0..N {
+                            if let Some(Some(v)) = stm.write_transaction(|tr| map.remove(tr, &i)) {
+                                assert_eq!(v, i);
+                                removed.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            for th in threads {
+                th.join().unwrap();
+            }
+            assert_eq!(removed.load(Ordering::Relaxed), N as usize);
+            assert_eq!(stm.write_transaction(|tr| map.len(tr)), Some(0));
+        }
+    }
+
+    #[test]
+    fn pop_waits_for_push() {
+        let stm = Arc::new(STM::new());
+        let queue = TQueue::<u64>::new(&stm, 1);
+
+        let consumer = {
+            let stm = stm.clone();
+            thread::spawn(move || stm.write_transaction(|tr| queue.pop(tr)))
+        };
+
+        thread::sleep(std::time::Duration::from_millis(50));
+        stm.write_transaction(|tr| queue.push(tr, 42));
+        assert_eq!(consumer.join().unwrap(), Some(42));
+    }
+}
@@ -1,63 +1,439 @@
 use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::marker::PhantomData;
+use std::mem::{size_of, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-// ストライプのサイズ
-const STRIPE_SIZE: usize = 8; // u64, 8バイト
+// ストライプのサイズのデフォルト値
+// ストライプのサイズは各型のconstジェネリクスで指定する
+pub const DEFAULT_STRIPE_SIZE: usize = 8; // u64, 8バイト
 
-// メモリの合計サイズ
-const MEM_SIZE: usize = 512; // 512バイト
+// メモリの初期サイズのデフォルト値。最初のセグメントのサイズとなる
+pub const MEM_SIZE: usize = 512; // 512バイト
 
-// メモリの型
-pub struct Memory {
-    mem: Vec<u8>,             // メモリ
-    lock_ver: Vec<AtomicU64>, // lock & version
-    global_clock: AtomicU64,  // global version-clock
+// セグメント数の上限
+// 初期サイズをcapとすると、i番目(i >= 1)のセグメントはcap * 2^(i - 1)バイトのため、
+// 合計でcap * 2^(MAX_SEGMENTS - 1)バイトまで拡張可能
+const MAX_SEGMENTS: usize = 32;
+
+// 実行中トランザクションのread-versionを登録するスロット数
+// スレッドごとにスロットを割り当て、スロットが埋まっている場合のみMutexで登録する
+const NUM_SLOTS: usize = 64;
+
+// 空きスロットを表す値
+const FREE_SLOT: u64 = u64::MAX;
 
-    // アドレスからストライプ番号に変換するためのシフト量
-    shift_size: u32,
+// false sharingを避けるため、キャッシュラインにアラインメントした値
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+// スレッドごとの番号。スロットの選択に利用する
+fn thread_index() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    INDEX.with(|i| *i)
 }
 
-impl Memory {
-    pub fn new() -> Self { // <1>
-        // メモリ領域を生成
-        let mem = [0].repeat(MEM_SIZE);
+// トランザクションのread-versionの登録先
+#[derive(Clone, Copy)]
+enum Slot {
+    Epoch(usize), // epochsのインデックス
+    Overflow,     // overflow
+}
 
-        // アドレスからストライプ番号へ変換するためのシフト量を計算
-        // ストライプのサイズは2^nにアラインメントされている必要あり
-        let shift = STRIPE_SIZE.trailing_zeros(); // <2>
+// 連続したメモリ領域
+// 一度確保したセグメントは移動も解放もしないため、
+// 拡張中も他のスレッドから安全に参照可能
+struct Segment {
+    mem: *mut u8,             // メモリ
+    len: usize,               // バイト数
+    lock_ver: Vec<AtomicU64>, // lock & version
+}
 
-        // lock & versionを初期化 <3>
+impl Segment {
+    // メモリを確保できない場合はNone
+    fn new(len: usize, shift: u32) -> Option<Self> {
+        let mut mem = Vec::new();
         let mut lock_ver = Vec::new();
-        for _ in 0..MEM_SIZE >> shift {
+        mem.try_reserve_exact(len).ok()?;
+        lock_ver.try_reserve_exact(len >> shift).ok()?;
+
+        mem.resize(len, 0u8);
+        let mem = Box::into_raw(mem.into_boxed_slice()) as *mut u8;
+
+        // lock & versionを初期化
+        for _ in 0..len >> shift {
             lock_ver.push(AtomicU64::new(0));
         }
 
+        Some(Segment { mem, len, lock_ver })
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(self.mem, self.len)));
+        }
+    }
+}
+
+// 領域の割り当て情報
+// ストライプ数ごとのフリーリストと、解放待ちの領域を管理する
+struct Allocator {
+    top: usize,                         // 未使用領域の先頭アドレス
+    sizes: HashMap<usize, usize>,       // 割り当て済み領域のアドレスからストライプ数
+    free: HashMap<usize, Vec<usize>>,   // ストライプ数からフリーリスト
+    limbo: Vec<(u64, usize, usize)>,    // 解放時のバージョン、アドレス、ストライプ数
+}
+
+impl Allocator {
+    fn new() -> Self {
+        Allocator {
+            top: 0,
+            sizes: HashMap::new(),
+            free: HashMap::new(),
+            limbo: Vec::new(),
+        }
+    }
+
+    // 解放待ちの領域のうち、参照し得るトランザクションが
+    // すべて終了したものをフリーリストに移動
+    fn reclaim(&mut self, oldest: Option<u64>) {
+        let mut i = 0;
+        while i < self.limbo.len() {
+            let (ver, addr, n) = self.limbo[i];
+            if oldest.is_none_or(|rv| ver <= rv) {
+                self.limbo.swap_remove(i);
+                self.free.entry(n).or_default().push(addr);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+// 解放しようとする領域の状態
+enum Region {
+    Allocated(usize), // 割り当て済み。ストライプ数
+    Freed,            // 並行するトランザクションが解放済み
+    Invalid,          // 未割り当て、または開始前に解放済み
+}
+
+// メモリの型
+// STRIPE_SIZEはストライプのサイズで、2^nである必要あり
+pub struct Memory<const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    segments: Vec<AtomicPtr<Segment>>, // セグメント
+    global_clock: AtomicU64,           // global version-clock
+
+    // 最初のセグメントのサイズ
+    capacity: usize,
+
+    // 領域の割り当て情報
+    allocator: Mutex<Allocator>,
+
+    // 実行中トランザクションのread-version。空きスロットはFREE_SLOT
+    epochs: Vec<CachePadded<AtomicU64>>,
+
+    // スロットに登録できなかったトランザクションのread-versionと、その数
+    overflow: Mutex<BTreeMap<u64, usize>>,
+
+    // retryしたトランザクションの待機用
+    retry_lock: Mutex<()>,
+    retry_cond: Condvar,
+    waiters: AtomicUsize, // 待機中のトランザクション数
+}
+
+impl<const STRIPE_SIZE: usize> Memory<STRIPE_SIZE> {
+    // ストライプのサイズの検査
+    // ストライプのサイズは2^nにアラインメントされている必要あり
+    // 定数として評価されるため、不正なサイズの場合はコンパイル時にエラーとなる
+    const STRIPE_SIZE_OK: () = assert!(
+        STRIPE_SIZE.is_power_of_two(),
+        "stripe size must be a power of two"
+    );
+
+    // アドレスからストライプ番号へ変換するためのシフト量 <2>
+    const SHIFT_SIZE: u32 = STRIPE_SIZE.trailing_zeros();
+
+    // capacityは最初のセグメントのサイズで、ストライプのサイズの倍数である必要あり
+    pub fn new(capacity: usize) -> Self { // <1>
+        let () = Self::STRIPE_SIZE_OK;
+        assert!(
+            capacity > 0 && capacity.is_multiple_of(STRIPE_SIZE),
+            "capacity must be a positive multiple of the stripe size"
+        );
+        // すべてのセグメントのアドレスがusizeに収まる必要あり
+        assert!(
+            capacity <= usize::MAX >> MAX_SEGMENTS,
+            "capacity is too large"
+        );
+
+        // 最初のセグメントのみ生成し、残りは必要になった時点で生成
+        let mut segments = Vec::new();
+        for _ in 0..MAX_SEGMENTS {
+            segments.push(AtomicPtr::new(ptr::null_mut()));
+        }
+        let first = Segment::new(capacity, Self::SHIFT_SIZE).expect("failed to allocate memory");
+        let first = Box::new(first);
+        segments[0].store(Box::into_raw(first), Ordering::Release);
+
         Memory {
-            mem,
-            lock_ver,
+            segments,
             global_clock: AtomicU64::new(0),
-            shift_size: shift,
+            capacity,
+            allocator: Mutex::new(Allocator::new()),
+            epochs: (0..NUM_SLOTS)
+                .map(|_| CachePadded(AtomicU64::new(FREE_SLOT)))
+                .collect(),
+            overflow: Mutex::new(BTreeMap::new()),
+            retry_lock: Mutex::new(()),
+            retry_cond: Condvar::new(),
+            waiters: AtomicUsize::new(0),
+        }
+    }
+
+    // アドレスが属するセグメント番号と、そのセグメントの先頭アドレスを計算
+    fn segment_of(&self, addr: usize) -> (usize, usize) {
+        if addr < self.capacity {
+            (0, 0)
+        } else {
+            let idx = (usize::BITS - (addr / self.capacity).leading_zeros()) as usize;
+            (idx, self.capacity << (idx - 1))
+        }
+    }
+
+    // セグメントのサイズ
+    fn segment_size(&self, idx: usize) -> usize {
+        if idx == 0 {
+            self.capacity
+        } else {
+            self.capacity << (idx - 1)
+        }
+    }
+
+    // アドレスが属するセグメントと、セグメント内のオフセットを取得
+    fn segment(&self, addr: usize) -> (&Segment, usize) {
+        let (idx, base) = self.segment_of(addr);
+        let seg = self.segments.get(idx).map_or(ptr::null_mut(), |p| {
+            p.load(Ordering::Acquire)
+        });
+        if seg.is_null() {
+            panic!("invalid address: {}", addr);
+        }
+        (unsafe { &*seg }, addr - base)
+    }
+
+    // 対象アドレスのlock & versionを取得
+    fn lock_ver(&self, addr: usize) -> &AtomicU64 {
+        let (seg, offset) = self.segment(addr);
+        &seg.lock_ver[offset >> Self::SHIFT_SIZE]
+    }
+
+    // 対象アドレスのストライプを読み込み。単なるコピー
+    fn read_stripe(&self, addr: usize) -> [u8; STRIPE_SIZE] {
+        let (seg, offset) = self.segment(addr);
+        let mut val = [0; STRIPE_SIZE];
+        unsafe {
+            ptr::copy_nonoverlapping(seg.mem.add(offset), val.as_mut_ptr(), STRIPE_SIZE);
+        }
+        val
+    }
+
+    // 対象アドレスのストライプに書き込み。単なるコピー
+    fn write_stripe(&self, addr: usize, val: &[u8; STRIPE_SIZE]) {
+        let (seg, offset) = self.segment(addr);
+        unsafe {
+            ptr::copy_nonoverlapping(val.as_ptr(), seg.mem.add(offset), STRIPE_SIZE);
+        }
+    }
+
+    // トランザクションの開始を登録し、read-versionと登録先を返す
+    // 登録後にglobal version-clockを読み直し、変化していなければ登録完了とする
+    // 解放待ち領域の回収は、global version-clockの更新後にスロットを走査するため、
+    // 走査で見落とされたトランザクションは必ず解放後のread-versionで開始する
+    fn begin(&self) -> (u64, Slot) {
+        let mut rv = self.global_clock.load(Ordering::SeqCst);
+
+        // スレッドごとのスロットから順に空きスロットを探索
+        let start = thread_index();
+        for i in 0..NUM_SLOTS {
+            let idx = (start + i) % NUM_SLOTS;
+            let epoch = &self.epochs[idx].0;
+            if epoch
+                .compare_exchange(FREE_SLOT, rv, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+
+            loop {
+                let now = self.global_clock.load(Ordering::SeqCst);
+                if now == rv {
+                    return (rv, Slot::Epoch(idx));
+                }
+                rv = now;
+                epoch.store(rv, Ordering::SeqCst);
+            }
+        }
+
+        // 空きスロットがない場合はMutexで登録
+        // global version-clockの読み込みと登録をアトミックに行う
+        let mut overflow = self.overflow.lock().unwrap();
+        let rv = self.global_clock.load(Ordering::SeqCst);
+        *overflow.entry(rv).or_insert(0) += 1;
+        (rv, Slot::Overflow)
+    }
+
+    // トランザクションの終了を登録
+    fn end(&self, rv: u64, slot: Slot) {
+        match slot {
+            Slot::Epoch(idx) => self.epochs[idx].0.store(FREE_SLOT, Ordering::Release),
+            Slot::Overflow => {
+                let mut overflow = self.overflow.lock().unwrap();
+                let n = overflow.get_mut(&rv).unwrap();
+                *n -= 1;
+                if *n == 0 {
+                    overflow.remove(&rv);
+                }
+            }
+        }
+    }
+
+    // 実行中トランザクションの最小のread-version
+    fn oldest(&self) -> Option<u64> {
+        let overflow = self.overflow.lock().unwrap().keys().next().copied();
+        self.epochs
+            .iter()
+            .map(|e| e.0.load(Ordering::SeqCst))
+            .filter(|rv| *rv != FREE_SLOT)
+            .chain(overflow)
+            .min()
+    }
+
+    // nストライプ分の領域を割り当て、先頭アドレスを返す
+    // メモリが不足した場合はセグメントを追加して拡張する
+    fn alloc(&self, n: usize) -> Option<usize> {
+        let size = n.checked_mul(STRIPE_SIZE)?;
+        let mut allocator = self.allocator.lock().unwrap();
+
+        // 解放待ち領域を回収してから、フリーリストを探索
+        // 解放待ちの領域がなければ走査を省略
+        if !allocator.limbo.is_empty() {
+            allocator.reclaim(self.oldest());
+        }
+        if let Some(addr) = allocator.free.get_mut(&n).and_then(|l| l.pop()) {
+            allocator.sizes.insert(addr, n);
+            return Some(addr);
+        }
+
+        // 未使用領域から割り当て
+        // 現在のセグメントに収まらない場合は、sizeが収まる最初のセグメントの先頭から割り当てる
+        // 途中のセグメントは生成せず、収まるセグメントがなければメモリ不足
+        let (mut idx, base) = self.segment_of(allocator.top);
+        if idx >= MAX_SEGMENTS {
+            return None; // メモリ不足
+        }
+        let mut top = allocator.top;
+        if top.checked_add(size)? > base + self.segment_size(idx) {
+            idx = (idx + 1..MAX_SEGMENTS).find(|i| self.segment_size(*i) >= size)?;
+            top = self.segment_size(idx); // 2番目以降のセグメントの先頭アドレスはサイズと等しい
+        }
+
+        // セグメントが未生成なら生成
+        if self.segments[idx].load(Ordering::Acquire).is_null() {
+            let seg = Box::new(Segment::new(self.segment_size(idx), Self::SHIFT_SIZE)?);
+            self.segments[idx].store(Box::into_raw(seg), Ordering::Release);
+        }
+
+        allocator.top = top + size;
+        allocator.sizes.insert(top, n);
+        Some(top)
+    }
+
+    // 解放しようとする領域の状態を取得
+    // read-versionより後にコミットされた解放は、解放待ちの領域から判定する
+    // 実行中のトランザクションのread-version以降に解放された領域は回収されないため、
+    // 解放待ちの領域に必ず残っている
+    fn region(&self, addr: usize, rv: u64) -> Region {
+        let allocator = self.allocator.lock().unwrap();
+        let mut limbo = allocator.limbo.iter();
+        if let Some(n) = allocator.sizes.get(&addr) {
+            Region::Allocated(*n)
+        } else if limbo.any(|(ver, a, _)| *a == addr && *ver > rv) {
+            Region::Freed
+        } else {
+            Region::Invalid
+        }
+    }
+
+    // 中断したトランザクションが割り当てた領域を即座に回収
+    // 他のトランザクションからは参照されていないため、再利用して問題ない
+    fn dealloc(&self, addr: usize) {
+        let mut allocator = self.allocator.lock().unwrap();
+        if let Some(n) = allocator.sizes.remove(&addr) {
+            allocator.free.entry(n).or_default().push(addr);
+        }
+    }
+
+    // コミットされた解放を登録
+    // バージョンverより前に開始したトランザクションがすべて終了するまで再利用しない
+    fn retire(&self, addr: usize, ver: u64) {
+        let mut allocator = self.allocator.lock().unwrap();
+        if let Some(n) = allocator.sizes.remove(&addr) {
+            allocator.limbo.push((ver, addr, n));
+        }
+    }
+
+    // read-set中のいずれかのストライプがread-versionより後に
+    // 更新されるまで待機
+    fn wait_change(&self, read_set: &HashSet<usize>, rv: u64) {
+        let mut guard = self.retry_lock.lock().unwrap();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        // コミット側のバージョン更新と待機者数の読み込みとの順序を保証
+        fence(Ordering::SeqCst);
+
+        while read_set.iter().all(|addr| self.test_not_modify(*addr, rv)) {
+            guard = self.retry_cond.wait(guard).unwrap();
+        }
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // 待機中のトランザクションを起床
+    // コミットでバージョンを更新した後に呼び出す
+    fn notify_change(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiters.load(Ordering::Relaxed) > 0 {
+            let _guard = self.retry_lock.lock().unwrap();
+            self.retry_cond.notify_all();
         }
     }
 
     // global version-clockをインクリメント <4>
+    // beginでのスロットへの登録と順序付けるためSeqCst
     fn inc_global_clock(&mut self) -> u64 {
-        self.global_clock.fetch_add(1, Ordering::AcqRel)
+        self.global_clock.fetch_add(1, Ordering::SeqCst)
     }
 
     // 対象のアドレスのバージョンを取得 <5>
     fn get_addr_ver(&self, addr: usize) -> u64 {
-        let idx = addr >> self.shift_size;
-        let n = self.lock_ver[idx].load(Ordering::Relaxed);
+        let n = self.lock_ver(addr).load(Ordering::Relaxed);
         n & !(1 << 63)
     }
 
     // 対象のアドレスのバージョンがrv以下でロックされていないかをテスト <6>
     fn test_not_modify(&self, addr: usize, rv: u64) -> bool {
-        let idx = addr >> self.shift_size;
-        let n = self.lock_ver[idx].load(Ordering::Relaxed);
+        let n = self.lock_ver(addr).load(Ordering::Relaxed);
         // ロックのビットは最上位ビットとするため、
         // 単にrvと比較するだけでテスト可能
         n <= rv
@@ -65,8 +441,7 @@ impl Memory {
 
     // 対象アドレスのロックを獲得 <7>
     fn lock_addr(&mut self, addr: usize) -> bool {
-        let idx = addr >> self.shift_size;
-        match self.lock_ver[idx].fetch_update( // <8>
+        self.lock_ver(addr).fetch_update( // <8>
             Ordering::Relaxed, // 書き込み時のオーダー
             Ordering::Relaxed, // 読み込み時のオーダー
             |val| {
@@ -78,34 +453,170 @@ impl Memory {
                     None
                 }
             },
-        ) {
-            Ok(_) => true,
-            Err(_) => false,
-        }
+        ).is_ok()
     }
 
     // 対象アドレスのロックを解放 <9>
     fn unlock_addr(&mut self, addr: usize) {
-        let idx = addr >> self.shift_size;
-        self.lock_ver[idx].fetch_and(!(1 << 63),
-                                     Ordering::Relaxed);
+        self.lock_ver(addr).fetch_and(!(1 << 63),
+                                      Ordering::Relaxed);
     }
 }
 
-pub struct ReadTrans<'a> { // <1>
-    read_ver: u64,   // read-version
-    is_abort: bool,  // 競合を検知した場合に真
-    mem: &'a Memory, // Memory型への参照
+impl<const STRIPE_SIZE: usize> Drop for Memory<STRIPE_SIZE> {
+    fn drop(&mut self) {
+        for seg in self.segments.iter() {
+            let p = seg.load(Ordering::Acquire);
+            if !p.is_null() {
+                unsafe { drop(Box::from_raw(p)) };
+            }
+        }
+    }
 }
 
-impl<'a> ReadTrans<'a> {
-    fn new(mem: &'a Memory) -> Self { // <2>
-        ReadTrans {
-            is_abort: false,
+impl<const STRIPE_SIZE: usize> Default for Memory<STRIPE_SIZE> {
+    fn default() -> Self {
+        Self::new(MEM_SIZE)
+    }
+}
+
+// TVarに格納できる型
+// TVarの値はストライプのバイト列から復元するため、解放済みの領域や別のSTMの領域を
+// 指すTVarを読み込んでも不正な値とならないよう、任意のバイト列が正しい値となり、
+// かつパディングを含まない型にのみ実装すること
+#[allow(clippy::missing_safety_doc)]
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, ());
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+// TVarは単なるアドレスのため、任意の値が正しいTVarとなる
+// 不正なアドレスのTVarを読み書きした場合はパニックする
+unsafe impl<T: 'static, const STRIPE_SIZE: usize> Pod for TVar<T, STRIPE_SIZE> {}
+
+// 型付きのトランザクショナル変数
+// Memory上の先頭アドレスのみを保持し、値は複数ストライプにまたがってもよい
+// STRIPE_SIZEは利用するSTMのストライプのサイズと一致させる
+pub struct TVar<T, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    addr: usize,
+    _marker: PhantomData<T>,
+}
+
+// TVarは単なるアドレスのためTによらずコピー可能
+impl<T, const STRIPE_SIZE: usize> Clone for TVar<T, STRIPE_SIZE> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, const STRIPE_SIZE: usize> Copy for TVar<T, STRIPE_SIZE> {}
+
+impl<T, const STRIPE_SIZE: usize> TVar<T, STRIPE_SIZE> {
+    // 値を格納するために必要なストライプ数
+    // サイズ0の型でも1ストライプを割り当てる
+    pub const NUM_STRIPES: usize = if size_of::<T>() == 0 {
+        1
+    } else {
+        size_of::<T>().div_ceil(STRIPE_SIZE)
+    };
+
+    // どの領域も指さないTVar。連結リストの末尾などに利用する
+    // 読み書きするとパニックする
+    pub fn null() -> Self {
+        TVar {
+            addr: usize::MAX,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == usize::MAX
+    }
+
+    // 先頭アドレス
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    // i番目のストライプのアドレス
+    fn stripe_addr(&self, i: usize) -> usize {
+        self.addr.wrapping_add(i * STRIPE_SIZE)
+    }
+
+    // alloc_arrayで割り当てた配列の、先頭からi番目の要素
+    // 範囲外を指定しても、他の領域を読み書きするかパニックするのみで未定義動作とはならない
+    pub fn offset(&self, i: usize) -> Self {
+        self.field(i * Self::NUM_STRIPES)
+    }
 
-            // global version-clock読み込み
-            read_ver: mem.global_clock.load(Ordering::Acquire),
+    // 先頭からi番目のストライプに格納した、型Uの値のTVar
+    // 複数の値を連続したストライプに格納する場合に利用する
+    pub fn field<U>(&self, i: usize) -> TVar<U, STRIPE_SIZE> {
+        TVar {
+            addr: self.stripe_addr(i),
+            _marker: PhantomData,
+        }
+    }
+}
 
+// 値をストライプ単位のバイト列に変換
+// Podはパディングを含まないため、すべてのバイトが初期化済み
+fn to_stripes<T: Pod, const STRIPE_SIZE: usize>(val: &T) -> Vec<[u8; STRIPE_SIZE]> {
+    let mut stripes = vec![[0; STRIPE_SIZE]; TVar::<T, STRIPE_SIZE>::NUM_STRIPES];
+    unsafe {
+        ptr::copy_nonoverlapping(
+            val as *const T as *const u8,
+            stripes.as_mut_ptr() as *mut u8,
+            size_of::<T>(),
+        );
+    }
+    stripes
+}
+
+// ストライプ単位のバイト列から値を復元
+// Podは任意のバイト列が正しい値となるため、どのストライプから復元してもよい
+fn from_stripes<T: Pod, const STRIPE_SIZE: usize>(stripes: &[[u8; STRIPE_SIZE]]) -> T {
+    assert_eq!(stripes.len(), TVar::<T, STRIPE_SIZE>::NUM_STRIPES);
+    let mut val = MaybeUninit::<T>::uninit();
+    unsafe {
+        ptr::copy_nonoverlapping(
+            stripes.as_ptr() as *const u8,
+            val.as_mut_ptr() as *mut u8,
+            size_of::<T>(),
+        );
+        val.assume_init()
+    }
+}
+
+pub struct ReadTrans<'a, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> { // <1>
+    read_ver: u64,                // read-version
+    slot: Slot,                   // read-versionの登録先
+    is_abort: bool,               // 競合を検知した場合に真
+    mem: &'a Memory<STRIPE_SIZE>, // Memory型への参照
+}
+
+impl<'a, const STRIPE_SIZE: usize> Drop for ReadTrans<'a, STRIPE_SIZE> {
+    fn drop(&mut self) {
+        self.mem.end(self.read_ver, self.slot);
+    }
+}
+
+impl<'a, const STRIPE_SIZE: usize> ReadTrans<'a, STRIPE_SIZE> {
+    fn new(mem: &'a Memory<STRIPE_SIZE>) -> Self { // <2>
+        // global version-clock読み込み
+        let (read_ver, slot) = mem.begin();
+
+        ReadTrans {
+            is_abort: false,
+            read_ver,
+            slot,
             mem,
         }
     }
@@ -118,6 +629,7 @@ impl<'a> ReadTrans<'a> {
         }
 
         // アドレスがストライプのアラインメントに沿っているかチェック
+        // TVarは任意のアドレスを指し得るため、TVarの読み込みでも検査する
         assert_eq!(addr & (STRIPE_SIZE - 1), 0); // <5>
 
         // 読み込みメモリがロックされておらず、read-version以下か判定 <6>
@@ -129,13 +641,7 @@ impl<'a> ReadTrans<'a> {
         fence(Ordering::Acquire);
 
         // メモリ読み込み。単なるコピー <7>
-        let mut mem = [0; STRIPE_SIZE];
-        for (dst, src) in mem
-            .iter_mut()
-            .zip(self.mem.mem[addr..addr + STRIPE_SIZE].iter())
-        {
-            *dst = *src;
-        }
+        let mem = self.mem.read_stripe(addr);
 
         fence(Ordering::SeqCst);
 
@@ -147,37 +653,89 @@ impl<'a> ReadTrans<'a> {
 
         Some(mem)
     }
+
+    // TVar読み込み関数
+    // すべてのストライプをread-versionで検証しながら読み込む
+    pub fn load_var<T: Pod>(&mut self, var: &TVar<T, STRIPE_SIZE>) -> Option<T> {
+        let n = TVar::<T, STRIPE_SIZE>::NUM_STRIPES;
+        let mut stripes = Vec::with_capacity(n);
+        for i in 0..n {
+            stripes.push(self.load(var.stripe_addr(i))?);
+        }
+        Some(from_stripes(&stripes))
+    }
 }
 
-pub struct WriteTrans<'a> {
+pub struct WriteTrans<'a, const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
     read_ver: u64,            // read-version
+    slot: Slot,               // read-versionの登録先
     read_set: HashSet<usize>, // read-set
     write_set: HashMap<usize, [u8; STRIPE_SIZE]>, // write-set
-    locked: Vec<usize>,  // ロック済みアドレス
-    is_abort: bool,      // 競合を検知した場合に真
-    mem: &'a mut Memory, // Memory型への参照
+    locked: Vec<usize>,    // ロック済みアドレス
+    allocated: Vec<usize>, // 割り当てた領域
+    freed: Vec<usize>,     // 解放した領域
+    is_commit: bool,       // コミットした場合に真
+    is_abort: bool,        // 競合を検知した場合に真
+    commit_hooks: Vec<Hook>, // コミット後に実行する関数
+    abort_hooks: Vec<Hook>,  // 中断時に実行する関数
+    mem: &'a mut Memory<STRIPE_SIZE>, // Memory型への参照
 }
 
-impl<'a> Drop for WriteTrans<'a> {
+// コミット時および中断時に実行する関数の型
+type Hook = Box<dyn FnOnce()>;
+
+// 入れ子のトランザクション開始時点の状態
+// 入れ子のトランザクションが中断した場合はこの状態まで巻き戻す
+struct Checkpoint<const STRIPE_SIZE: usize> {
+    write_set: HashMap<usize, [u8; STRIPE_SIZE]>,
+    num_allocated: usize,
+    num_freed: usize,
+    num_commit_hooks: usize,
+    num_abort_hooks: usize,
+}
+
+impl<'a, const STRIPE_SIZE: usize> Drop for WriteTrans<'a, STRIPE_SIZE> {
     fn drop(&mut self) {
         // ロック済みアドレスのロックを解放
         for addr in self.locked.iter() {
             self.mem.unlock_addr(*addr);
         }
+
+        // コミットしなかった場合は割り当てた領域を回収
+        if !self.is_commit {
+            for addr in self.allocated.iter() {
+                self.mem.dealloc(*addr);
+            }
+        }
+
+        self.mem.end(self.read_ver, self.slot);
+
+        // コミットしなかった場合は中断時の関数を実行
+        if !self.is_commit {
+            for hook in self.abort_hooks.drain(..) {
+                hook();
+            }
+        }
     }
 }
 
-impl<'a> WriteTrans<'a> {
-    fn new(mem: &'a mut Memory) -> Self { // <1>
+impl<'a, const STRIPE_SIZE: usize> WriteTrans<'a, STRIPE_SIZE> {
+    fn new(mem: &'a mut Memory<STRIPE_SIZE>) -> Self { // <1>
+        // global version-clock読み込み
+        let (read_ver, slot) = mem.begin();
+
         WriteTrans {
             read_set: HashSet::new(),
             write_set: HashMap::new(),
             locked: Vec::new(),
+            allocated: Vec::new(),
+            freed: Vec::new(),
+            is_commit: false,
             is_abort: false,
-
-            // global version-clock読み込み
-            read_ver: mem.global_clock.load(Ordering::Acquire),
-
+            commit_hooks: Vec::new(),
+            abort_hooks: Vec::new(),
+            read_ver,
+            slot,
             mem,
         }
     }
@@ -216,13 +774,7 @@ impl<'a> WriteTrans<'a> {
         fence(Ordering::Acquire);
 
         // メモリ読み込み。単なるコピー
-        let mut mem = [0; STRIPE_SIZE];
-        for (dst, src) in mem
-            .iter_mut()
-            .zip(self.mem.mem[addr..addr + STRIPE_SIZE].iter())
-        {
-            *dst = *src;
-        }
+        let mem = self.mem.read_stripe(addr);
 
         fence(Ordering::SeqCst);
 
@@ -235,6 +787,196 @@ impl<'a> WriteTrans<'a> {
         Some(mem)
     }
 
+    // TVar書き込み関数
+    // 値をストライプに分割してwrite-setに追加
+    pub fn store_var<T: Pod>(&mut self, var: &TVar<T, STRIPE_SIZE>, val: T) {
+        for (i, stripe) in to_stripes(&val).into_iter().enumerate() {
+            self.store(var.stripe_addr(i), stripe);
+        }
+    }
+
+    // TVar読み込み関数
+    pub fn load_var<T: Pod>(&mut self, var: &TVar<T, STRIPE_SIZE>) -> Option<T> {
+        let n = TVar::<T, STRIPE_SIZE>::NUM_STRIPES;
+        let mut stripes = Vec::with_capacity(n);
+        for i in 0..n {
+            stripes.push(self.load(var.stripe_addr(i))?);
+        }
+        Some(from_stripes(&stripes))
+    }
+
+    // sizeバイトの領域を割り当て、先頭アドレスを返す
+    // 領域は0で初期化され、トランザクションが中断した場合は回収される
+    // メモリの上限に達した場合はNoneを返す
+    pub fn alloc(&mut self, size: usize) -> Option<usize> {
+        let n = size.div_ceil(STRIPE_SIZE).max(1);
+        let addr = self.mem.alloc(n)?;
+        self.allocated.push(addr);
+
+        // 0で初期化。コミット時にバージョンも更新される
+        for i in 0..n {
+            self.write_set.insert(addr + i * STRIPE_SIZE, [0; STRIPE_SIZE]);
+        }
+        Some(addr)
+    }
+
+    // allocで割り当てた領域を解放
+    // 実際の再利用は、コミット前に開始したトランザクションがすべて終了した後となる
+    // 割り当てられていない領域や、解放済みの領域を解放した場合はpanic
+    pub fn free(&mut self, addr: usize) {
+        if self.freed.contains(&addr) {
+            panic!("double free: {}", addr);
+        }
+
+        let n = match self.mem.region(addr, self.read_ver) {
+            Region::Allocated(n) => n,
+            // 並行して同じ領域を解放したトランザクションが先にコミットした場合、
+            // このトランザクションの読み込みは古いため中断して再実行する
+            Region::Freed => {
+                self.is_abort = true;
+                return;
+            }
+            Region::Invalid => panic!("free of unallocated address: {}", addr),
+        };
+
+        // 0を書き込むことで、コミット時にロック獲得とバージョン更新を行い、
+        // 解放した領域を読み込んでいる他のトランザクションと競合させる
+        for i in 0..n {
+            self.write_set.insert(addr + i * STRIPE_SIZE, [0; STRIPE_SIZE]);
+        }
+        self.freed.push(addr);
+    }
+
+    // nストライプの領域を割り当て、先頭のストライプのTVarを返す
+    // fieldで取得したTVarを用いて、複数の値を連続したストライプに格納する場合に利用する
+    pub fn alloc_stripes<T>(&mut self, n: usize) -> Option<TVar<T, STRIPE_SIZE>> {
+        Some(TVar {
+            addr: self.alloc(n.checked_mul(STRIPE_SIZE)?)?,
+            _marker: PhantomData,
+        })
+    }
+
+    // TVarを割り当てて初期値を書き込む
+    pub fn alloc_var<T: Pod>(&mut self, val: T) -> Option<TVar<T, STRIPE_SIZE>> {
+        let var = TVar {
+            addr: self.alloc(size_of::<T>())?,
+            _marker: PhantomData,
+        };
+        self.store_var(&var, val);
+        Some(var)
+    }
+
+    // 要素数lenのTVarの配列を割り当て、すべての要素にvalを書き込む
+    // 先頭の要素を返し、i番目の要素はoffsetで取得する
+    pub fn alloc_array<T: Pod>(&mut self, len: usize, val: T) -> Option<TVar<T, STRIPE_SIZE>> {
+        let n = TVar::<T, STRIPE_SIZE>::NUM_STRIPES;
+        let var = TVar {
+            addr: self.alloc(len.max(1).checked_mul(n)?.checked_mul(STRIPE_SIZE)?)?,
+            _marker: PhantomData,
+        };
+        for i in 0..len {
+            self.store_var(&var.offset(i), val);
+        }
+        Some(var)
+    }
+
+    // alloc_varおよびalloc_arrayで割り当てたTVarを解放
+    pub fn free_var<T>(&mut self, var: TVar<T, STRIPE_SIZE>) {
+        self.free(var.addr);
+    }
+
+    // コミット後に実行する関数を登録
+    // トランザクションは何度も再実行され得るため、
+    // 入出力などの副作用はトランザクション中ではなくこの関数で行う
+    // 登録した順に、ロックを解放した後で実行される
+    pub fn on_commit<F: FnOnce() + 'static>(&mut self, f: F) {
+        self.commit_hooks.push(Box::new(f));
+    }
+
+    // 中断時に実行する関数を登録
+    // 競合による再実行、retryによる待機、Abortによる中止のいずれでも実行される
+    // 入れ子のトランザクション中に登録した場合は、その入れ子の中断時にも実行される
+    pub fn on_abort<F: FnOnce() + 'static>(&mut self, f: F) {
+        self.abort_hooks.push(Box::new(f));
+    }
+
+    // 現在の状態を保存
+    fn checkpoint(&self) -> Checkpoint<STRIPE_SIZE> {
+        Checkpoint {
+            write_set: self.write_set.clone(),
+            num_allocated: self.allocated.len(),
+            num_freed: self.freed.len(),
+            num_commit_hooks: self.commit_hooks.len(),
+            num_abort_hooks: self.abort_hooks.len(),
+        }
+    }
+
+    // 保存した状態まで書き込みと割り当てを取り消し
+    // 読み込みの結果によって中断を判断しているため、read-setはそのまま残す
+    fn rollback(&mut self, cp: Checkpoint<STRIPE_SIZE>) {
+        self.write_set = cp.write_set;
+        for addr in self.allocated.split_off(cp.num_allocated) {
+            self.mem.dealloc(addr);
+        }
+        self.freed.truncate(cp.num_freed);
+
+        // 取り消した部分で登録された関数のうち、中断時の関数のみ実行
+        self.commit_hooks.truncate(cp.num_commit_hooks);
+        for hook in self.abort_hooks.split_off(cp.num_abort_hooks) {
+            hook();
+        }
+    }
+
+    // 入れ子のトランザクション
+    // fがOkを返した場合、fの読み込みと書き込みは親トランザクションに統合され、
+    // 親トランザクションのコミット時にまとめてコミットされる
+    // fがAbortを返した場合はfの書き込みのみを取り消し、親トランザクションは継続する
+    // retryと競合は親トランザクションに伝播するため、Retryはそのまま返すこと
+    pub fn nested<F, R>(&mut self, f: F) -> STMResult<R>
+    where
+        F: FnOnce(&mut Self) -> STMResult<R>,
+    {
+        let cp = self.checkpoint();
+        match f(self) {
+            STMResult::Abort => {
+                self.rollback(cp);
+                STMResult::Abort
+            }
+            result => result,
+        }
+    }
+
+    // f1を実行し、f1がretryした場合はf1の書き込みを破棄してf2を実行
+    // f1の読み込みはread-setに残るため、f2もretryした場合は
+    // 両方の読み込み対象の更新を待機することになる
+    pub fn or_else<F1, F2, R>(&mut self, f1: F1, f2: F2) -> STMResult<R>
+    where
+        F1: FnOnce(&mut Self) -> STMResult<R>,
+        F2: FnOnce(&mut Self) -> STMResult<R>,
+    {
+        let cp = self.checkpoint();
+        let result = f1(self);
+
+        // 競合を検知した場合は親トランザクションごと再実行
+        if self.is_abort {
+            return result;
+        }
+
+        match result {
+            STMResult::Abort => {
+                // nestedと同様にf1の書き込みと割り当てを取り消して中断
+                self.rollback(cp);
+                STMResult::Abort
+            }
+            STMResult::Ok(_) => result,
+            STMResult::Retry => {
+                // f1の書き込みと割り当てを取り消してf2を実行
+                self.rollback(cp);
+                f2(self)
+            }
+        }
+    }
+
     // write-set中のアドレスをロック
     // すべてのアドレスをロック獲得できた場合は真をリターンする <4>
     fn lock_write_set(&mut self) -> bool {
@@ -275,52 +1017,362 @@ impl<'a> WriteTrans<'a> {
     fn commit(&mut self, ver: u64) {
         // すべてのアドレスに対して書き込み。単なるメモリコピー
         for (addr, val) in self.write_set.iter() {
-            let addr = *addr as usize;
-            for (dst, src) in self.mem.mem[addr..addr + STRIPE_SIZE].iter_mut().zip(val) {
-                *dst = *src;
-            }
+            self.mem.write_stripe(*addr, val);
         }
 
         fence(Ordering::Release);
 
         // すべてのアドレスのロック解放&バージョン更新
         for (addr, _) in self.write_set.iter() {
-            let idx = addr >> self.mem.shift_size;
-            self.mem.lock_ver[idx].store(ver, Ordering::Relaxed);
+            self.mem.lock_ver(*addr).store(ver, Ordering::Relaxed);
         }
 
         // ロック済みアドレス集合をクリア
         self.locked.clear();
+
+        // retryで待機中のトランザクションを起床
+        self.mem.notify_change();
+
+        // 解放した領域を解放待ちとして登録
+        for addr in self.freed.iter() {
+            self.mem.retire(*addr, ver);
+        }
+        self.is_commit = true;
     }
 }
 
 pub enum STMResult<T> {
     Ok(T),
-    Retry, // トランザクションをリトライ
+    // トランザクションをリトライ
+    // 書き込みトランザクションでは、読み込んだストライプが更新されるまで待機する
+    Retry,
     Abort, // トランザクションを中止
 }
 
-pub struct STM {
-    mem: UnsafeCell<Memory>, // 実際のメモリ
+// トランザクションが中断した理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbortReason {
+    Validation, // 読み込み時またはコミット時のread-set検証に失敗
+    Lock,       // write-setのロック獲得に失敗
+}
+
+// 中断したトランザクションの情報
+#[derive(Clone, Copy, Debug)]
+pub struct Conflict {
+    pub aborts: usize,       // このトランザクションの中断回数
+    pub karma: usize,        // 中断までに読み書きしたストライプ数の累計
+    pub reason: AbortReason, // 直前の中断理由
+}
+
+// 中断後の再実行方法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    Restart,   // 通常どおり投機的に再実行
+    Exclusive, // グローバルロックを獲得し、他の書き込みトランザクションを止めて再実行
+}
+
+// 競合管理
+// トランザクションが中断するたびに呼び出され、再実行までの待機と再実行方法を決める
+pub trait ContentionManager: Send + Sync {
+    fn on_abort(&self, conflict: &Conflict) -> Resolution;
+
+    // コミット時に呼び出される。conflictは中断がなければNone
+    fn on_commit(&self, _conflict: Option<&Conflict>) {}
+}
+
+// 指定時間待機。短い時間はスピンし、長い時間はスリープする
+fn pause(d: Duration) {
+    if d >= Duration::from_micros(100) {
+        thread::sleep(d);
+    } else {
+        let start = Instant::now();
+        while start.elapsed() < d {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// 待機せずに即座に再実行
+pub struct Aggressive;
+
+impl ContentionManager for Aggressive {
+    fn on_abort(&self, _conflict: &Conflict) -> Resolution {
+        Resolution::Restart
+    }
+}
+
+// 指数バックオフ
+// 中断するたびに待機時間を2倍にし、maxで打ち止めにする
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_micros(1), Duration::from_millis(1))
+    }
+}
+
+impl ContentionManager for Backoff {
+    fn on_abort(&self, conflict: &Conflict) -> Resolution {
+        let shift = (conflict.aborts - 1).min(20) as u32;
+        pause((self.min * (1 << shift)).min(self.max));
+        Resolution::Restart
+    }
+}
+
+// karmaによる優先度制御
+// 中断までに多くのストライプを読み書きしたトランザクションほど優先度が高いとみなし、
+// 待機時間を短くして先に再実行させる
+pub struct Karma {
+    max: Duration, // karmaが0の場合の待機時間
+}
+
+impl Karma {
+    pub fn new(max: Duration) -> Self {
+        Karma { max }
+    }
+}
+
+impl Default for Karma {
+    fn default() -> Self {
+        Karma::new(Duration::from_micros(100))
+    }
+}
+
+impl ContentionManager for Karma {
+    fn on_abort(&self, conflict: &Conflict) -> Resolution {
+        // u32への変換で桁あふれして0除算とならないよう飽和させる
+        let karma = conflict.karma.saturating_add(1).min(u32::MAX as usize) as u32;
+        pause(self.max / karma);
+        Resolution::Restart
+    }
+}
+
+// 飢餓状態の防止
+// threshold回中断したトランザクションはグローバルロックを獲得して排他的に実行し、
+// それまではinnerの方針に従う
+pub struct GlobalLockFallback<C> {
+    threshold: usize,
+    inner: C,
+}
+
+impl<C: ContentionManager> GlobalLockFallback<C> {
+    pub fn new(threshold: usize, inner: C) -> Self {
+        GlobalLockFallback { threshold, inner }
+    }
+}
+
+impl<C: ContentionManager> ContentionManager for GlobalLockFallback<C> {
+    fn on_abort(&self, conflict: &Conflict) -> Resolution {
+        if conflict.aborts >= self.threshold {
+            Resolution::Exclusive
+        } else {
+            self.inner.on_abort(conflict)
+        }
+    }
+
+    fn on_commit(&self, conflict: Option<&Conflict>) {
+        self.inner.on_commit(conflict);
+    }
+}
+
+// トランザクションの統計情報
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub commits: u64,           // コミット数
+    pub validation_aborts: u64, // read-setの検証失敗による中断数
+    pub lock_aborts: u64,       // ロック獲得失敗による中断数
+    pub retries: u64,           // retryによる待機数
+    pub exclusive_runs: u64,    // グローバルロックを獲得して排他的に実行した回数
+}
+
+// 統計情報のカウンタ
+#[derive(Default)]
+struct Counters {
+    commits: AtomicU64,
+    validation_aborts: AtomicU64,
+    lock_aborts: AtomicU64,
+    retries: AtomicU64,
+    exclusive_runs: AtomicU64,
+}
+
+impl Counters {
+    fn abort(&self, reason: AbortReason) {
+        let counter = match reason {
+            AbortReason::Validation => &self.validation_aborts,
+            AbortReason::Lock => &self.lock_aborts,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// グローバルロックのガード
+// 通常の実行では実行中の書き込みトランザクション数のカウンタを、
+// 排他的に実行するトランザクションの待機中は共有ロックを、
+// 排他的な実行では排他ロックを保持する
+enum SerialGuard<'a> {
+    Unlocked(&'a AtomicUsize),
+    Shared(RwLockReadGuard<'a, ()>),
+    Exclusive(RwLockWriteGuard<'a, ()>, &'a AtomicUsize),
+}
+
+impl<'a> Drop for SerialGuard<'a> {
+    fn drop(&mut self) {
+        match self {
+            SerialGuard::Unlocked(writers) => {
+                writers.fetch_sub(1, Ordering::SeqCst);
+            }
+            SerialGuard::Shared(_) => (),
+            SerialGuard::Exclusive(_, exclusive) => {
+                exclusive.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct STM<const STRIPE_SIZE: usize = DEFAULT_STRIPE_SIZE> {
+    mem: UnsafeCell<Memory<STRIPE_SIZE>>, // 実際のメモリ
+
+    // 排他的に実行するトランザクション用のグローバルロック
+    // 通常の書き込みトランザクションは、排他的に実行するトランザクションが
+    // ない間はロックを獲得せず、スレッドごとのカウンタのみを更新する
+    serial: RwLock<()>,
+    exclusive: AtomicUsize, // 排他的に実行中または待機中のトランザクション数
+    writers: Vec<CachePadded<AtomicUsize>>, // ロックなしで実行中の書き込みトランザクション数
+
+    cm: Box<dyn ContentionManager>, // 競合管理
+    stats: Counters,                // 統計情報
 }
 
 // スレッド間で共有可能に設定。チャネルで送受信可能に設定。
-unsafe impl Sync for STM {}
-unsafe impl Send for STM {}
+unsafe impl<const STRIPE_SIZE: usize> Sync for STM<STRIPE_SIZE> {}
+unsafe impl<const STRIPE_SIZE: usize> Send for STM<STRIPE_SIZE> {}
 
-impl STM {
+impl<const STRIPE_SIZE: usize> STM<STRIPE_SIZE> {
     pub fn new() -> Self {
+        Self::with_config(MEM_SIZE, Backoff::default())
+    }
+
+    // メモリの初期サイズを指定して生成
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_config(capacity, Backoff::default())
+    }
+
+    // 競合管理の方針を指定して生成
+    pub fn with_contention_manager<C>(cm: C) -> Self
+    where
+        C: ContentionManager + 'static,
+    {
+        Self::with_config(MEM_SIZE, cm)
+    }
+
+    // メモリの初期サイズと競合管理の方針を指定して生成
+    pub fn with_config<C>(capacity: usize, cm: C) -> Self
+    where
+        C: ContentionManager + 'static,
+    {
         STM {
-            mem: UnsafeCell::new(Memory::new()),
+            mem: UnsafeCell::new(Memory::new(capacity)),
+            serial: RwLock::new(()),
+            exclusive: AtomicUsize::new(0),
+            writers: (0..NUM_SLOTS)
+                .map(|_| CachePadded(AtomicUsize::new(0)))
+                .collect(),
+            cm: Box::new(cm),
+            stats: Counters::default(),
+        }
+    }
+
+    // 統計情報を取得
+    pub fn stats(&self) -> Stats {
+        Stats {
+            commits: self.stats.commits.load(Ordering::Relaxed),
+            validation_aborts: self.stats.validation_aborts.load(Ordering::Relaxed),
+            lock_aborts: self.stats.lock_aborts.load(Ordering::Relaxed),
+            retries: self.stats.retries.load(Ordering::Relaxed),
+            exclusive_runs: self.stats.exclusive_runs.load(Ordering::Relaxed),
+        }
+    }
+
+    // グローバルロックを獲得
+    // カウンタの更新とフラグの読み込みをSeqCstで行うことで、
+    // 排他的な実行とロックなしの実行の少なくとも一方が他方を検知する
+    fn lock_serial(&self, exclusive: bool) -> SerialGuard<'_> {
+        if exclusive {
+            self.stats.exclusive_runs.fetch_add(1, Ordering::Relaxed);
+            self.exclusive.fetch_add(1, Ordering::SeqCst);
+            // 排他的な実行中のpanicでロックが汚染されても、
+            // 保護するデータはないため、そのまま獲得する
+            let guard = self.serial.write().unwrap_or_else(PoisonError::into_inner);
+
+            // ロックなしで実行中の書き込みトランザクションの終了を待機
+            while self.writers.iter().any(|w| w.0.load(Ordering::SeqCst) != 0) {
+                thread::yield_now();
+            }
+            SerialGuard::Exclusive(guard, &self.exclusive)
+        } else {
+            let writers = &self.writers[thread_index() % NUM_SLOTS].0;
+            writers.fetch_add(1, Ordering::SeqCst);
+            if self.exclusive.load(Ordering::SeqCst) == 0 {
+                return SerialGuard::Unlocked(writers);
+            }
+
+            // 排他的に実行するトランザクションがある場合は、その終了を共有ロックで待機
+            writers.fetch_sub(1, Ordering::SeqCst);
+            SerialGuard::Shared(self.serial.read().unwrap_or_else(PoisonError::into_inner))
+        }
+    }
+
+    // 中断を記録し、競合管理の方針に従って待機
+    // 排他的に再実行すべき場合は真を返す
+    fn on_abort(&self, conflict: &mut Conflict, reason: AbortReason, work: usize) -> bool {
+        conflict.aborts += 1;
+        conflict.karma += work;
+        conflict.reason = reason;
+        self.stats.abort(reason);
+        self.cm.on_abort(conflict) == Resolution::Exclusive
+    }
+
+    // コミットを記録
+    fn on_commit(&self, conflict: &Conflict) {
+        self.stats.commits.fetch_add(1, Ordering::Relaxed);
+        if conflict.aborts == 0 {
+            self.cm.on_commit(None);
+        } else {
+            self.cm.on_commit(Some(conflict));
         }
     }
 
     // 読み込みトランザクション <1>
     pub fn read_transaction<F, R>(&self, f: F) -> Option<R>
     where
-        F: Fn(&mut ReadTrans) -> STMResult<R>,
+        F: Fn(&mut ReadTrans<STRIPE_SIZE>) -> STMResult<R>,
     {
+        let mut conflict = Conflict {
+            aborts: 0,
+            karma: 0,
+            reason: AbortReason::Validation,
+        };
+        let mut exclusive = false;
+
         loop {
+            // 排他的に実行する場合のみグローバルロックを獲得
+            // 通常の読み込みトランザクションは書き込みトランザクションを妨げない
+            let serial = if exclusive {
+                Some(self.lock_serial(true))
+            } else {
+                None
+            };
+
             // 1. global version-clock読み込み <2>
             let mut tr = ReadTrans::new(unsafe { &*self.mem.get() });
 
@@ -328,67 +1380,160 @@ impl STM {
             match f(&mut tr) {
                 STMResult::Abort => return None, // 中断
                 STMResult::Retry => {
-                    if tr.is_abort {
-                        continue; // リトライ
+                    if !tr.is_abort {
+                        return None; // 中断
                     }
-                    return None; // 中断
                 }
                 STMResult::Ok(val) => {
-                    if tr.is_abort == true {
-                        continue; // リトライ
-                    } else {
+                    if !tr.is_abort {
+                        self.on_commit(&conflict);
                         return Some(val); // 3. コミット
                     }
                 }
             }
+
+            // リトライ
+            drop(tr);
+            drop(serial);
+            exclusive |= self.on_abort(&mut conflict, AbortReason::Validation, 0);
         }
     }
 
     // 書き込みトランザクション <4>
+    // fの中で同じSTMのトランザクションを開始してはならない
+    // 独立したトランザクションとなりアトミック性が失われるため、
+    // 他のトランザクションを合成する場合はWriteTrans::nestedを用いる
     pub fn write_transaction<F, R>(&self, f: F) -> Option<R>
     where
-        F: Fn(&mut WriteTrans) -> STMResult<R>,
+        F: Fn(&mut WriteTrans<STRIPE_SIZE>) -> STMResult<R>,
     {
+        let mut conflict = Conflict {
+            aborts: 0,
+            karma: 0,
+            reason: AbortReason::Validation,
+        };
+        let mut exclusive = false;
+
         loop {
+            // グローバルロックを獲得
+            let serial = self.lock_serial(exclusive);
+
             // 1. global version-clock読み込み <5>
             let mut tr = WriteTrans::new(unsafe { &mut *self.mem.get() });
 
             // 2. 投機的実行 <6>
-            let result;
-            match f(&mut tr) {
+            let reason = match f(&mut tr) {
                 STMResult::Abort => return None,
-                STMResult::Retry => {
-                    if tr.is_abort {
-                        continue;
+                STMResult::Retry if !tr.is_abort => {
+                    // 何も読み込んでいない場合は再実行しても結果が変わらないため中断
+                    if tr.read_set.is_empty() {
+                        return None;
                     }
-                    return None;
+
+                    // 読み込んだストライプのいずれかが更新されるまで待機してから再実行
+                    // 待機中に解放待ち領域の回収や他のトランザクションを妨げないよう、
+                    // 先にトランザクションを終了してグローバルロックを解放
+                    let read_set = std::mem::take(&mut tr.read_set);
+                    let read_ver = tr.read_ver;
+                    drop(tr);
+                    drop(serial);
+                    self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                    unsafe { &*self.mem.get() }.wait_change(&read_set, read_ver);
+                    continue;
                 }
-                STMResult::Ok(val) => {
-                    if tr.is_abort {
-                        continue;
+                STMResult::Ok(val) if !tr.is_abort => {
+                    // 3. write-setのロック <7>
+                    if tr.lock_write_set() {
+                        // 4. global version-clockのインクリメント <8>
+                        let ver = 1 + tr.mem.inc_global_clock();
+
+                        // 5. read-setの検証 <9>
+                        if tr.read_ver + 1 == ver || tr.validate_read_set() {
+                            // 6. コミットとリリース <10>
+                            tr.commit(ver);
+                            let hooks = std::mem::take(&mut tr.commit_hooks);
+                            drop(tr);
+                            drop(serial);
+                            self.on_commit(&conflict);
+
+                            // コミット後に実行する関数を実行
+                            for hook in hooks {
+                                hook();
+                            }
+                            return Some(val);
+                        }
+                        AbortReason::Validation
+                    } else {
+                        AbortReason::Lock
                     }
-                    result = val;
                 }
-            }
+                _ => AbortReason::Validation, // 読み込み時に競合を検知
+            };
 
-            // 3. write-setのロック <7>
-            if !tr.lock_write_set() {
-                continue;
-            }
+            // 中断したため、競合管理の方針に従って再実行
+            let work = tr.read_set.len() + tr.write_set.len();
+            drop(tr);
+            drop(serial);
+            exclusive |= self.on_abort(&mut conflict, reason, work);
+        }
+    }
 
-            // 4. global version-clockのインクリメント <8>
-            let ver = 1 + tr.mem.inc_global_clock();
+    // 取り消し不可能なトランザクション
+    // グローバルロックを排他的に獲得して他の書き込みトランザクションを止め、
+    // global version-clockを占有した状態で一度だけ実行するため、中断も再実行もされない
+    // したがって、fの中で入出力などの副作用を直接実行してよい
+    // fがAbortまたはRetryを返した場合は書き込みを破棄してNoneを返すが、
+    // 実行済みの副作用は取り消されない。再実行できないため、Retryでも待機しない
+    // 実行中の書き込みトランザクションの終了を待機するため、
+    // 同じSTMのトランザクションの中で呼び出すとデッドロックする
+    pub fn irrevocable_transaction<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut WriteTrans<STRIPE_SIZE>) -> STMResult<R>,
+    {
+        let serial = self.lock_serial(true);
+        let mut tr = WriteTrans::new(unsafe { &mut *self.mem.get() });
 
-            // 5. read-setの検証 <9>
-            if tr.read_ver + 1 != ver && !tr.validate_read_set() {
-                continue;
-            }
+        // 他の書き込みトランザクションは存在しないため、
+        // 読み込み時の検証とロック獲得は必ず成功し、read-setの検証も不要
+        // 失敗した場合も、ロックを解放して中止する
+        let val = match f(&mut tr) {
+            STMResult::Ok(val) if !tr.is_abort => val,
+            _ => return None,
+        };
+        if !tr.lock_write_set() {
+            return None;
+        }
+        let ver = 1 + tr.mem.inc_global_clock();
+        tr.commit(ver);
 
-            // 6. コミットとリリース <10>
-            tr.commit(ver);
+        let hooks = std::mem::take(&mut tr.commit_hooks);
+        drop(tr);
+        drop(serial);
+        self.on_commit(&Conflict {
+            aborts: 0,
+            karma: 0,
+            reason: AbortReason::Validation,
+        });
 
-            return Some(result);
+        for hook in hooks {
+            hook();
         }
+        Some(val)
+    }
+
+    // TVarを生成し、初期値を書き込む
+    pub fn new_tvar<T: Pod>(&self, val: T) -> TVar<T, STRIPE_SIZE> {
+        self.write_transaction(|tr| match tr.alloc_var(val) {
+            Some(var) => STMResult::Ok(var),
+            None => STMResult::Abort,
+        })
+        .expect("out of memory")
+    }
+}
+
+impl<const STRIPE_SIZE: usize> Default for STM<STRIPE_SIZE> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -411,4 +1556,469 @@ macro_rules! store {
     ($t:ident, $a:expr, $v:expr) => {
         $t.store($a, $v)
     };
-}
\ No newline at end of file
+}
+
+// TVar読み込み用のマクロ
+#[macro_export]
+macro_rules! load_var {
+    ($t:ident, $v:expr) => {
+        if let Some(v) = ($t).load_var(&$v) {
+            v
+        } else {
+            // 読み込みに失敗したらリトライ
+            return tl2::STMResult::Retry;
+        }
+    };
+}
+
+// TVar書き込み用のマクロ
+#[macro_export]
+macro_rules! store_var {
+    ($t:ident, $v:expr, $val:expr) => {
+        $t.store_var(&$v, $val)
+    };
+}
+
+// STMResultを返す処理を呼び出し、Ok以外はそのまま呼び出し元に返すマクロ
+// コレクションの操作などを組み合わせる際に利用する
+#[macro_export]
+macro_rules! stm_try {
+    ($e:expr) => {
+        match $e {
+            tl2::STMResult::Ok(v) => v,
+            tl2::STMResult::Retry => return tl2::STMResult::Retry,
+            tl2::STMResult::Abort => return tl2::STMResult::Abort,
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tl2;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    #[test]
+    fn freed_region_is_reused() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar([1u64; 4]);
+        stm.write_transaction(|tr| {
+            tr.free_var(a);
+            STMResult::Ok(())
+        });
+
+        // 実行中のトランザクションがないため、解放した領域はすぐに再利用される
+        let b = stm.new_tvar([2u64; 4]);
+        assert_eq!(a.addr(), b.addr());
+        let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, b)));
+        assert_eq!(val, Some([2; 4]));
+    }
+
+    #[test]
+    fn freed_region_waits_for_running_transactions() {
+        let stm: Arc<STM> = Arc::new(STM::new());
+        let a = stm.new_tvar(1u64);
+
+        // 解放前に開始したトランザクションを実行中のままにする
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (finish_tx, finish_rx) = std::sync::mpsc::channel::<()>();
+        let stm2 = stm.clone();
+        let reader = std::thread::spawn(move || {
+            stm2.read_transaction(|tr| {
+                let val = load_var!(tr, a);
+                started_tx.send(()).unwrap();
+                finish_rx.recv().unwrap();
+                STMResult::Ok(val)
+            })
+        });
+        started_rx.recv().unwrap();
+
+        stm.write_transaction(|tr| {
+            tr.free_var(a);
+            STMResult::Ok(())
+        });
+
+        // 実行中のトランザクションが参照し得るため、まだ再利用されない
+        let b = stm.new_tvar(2u64);
+        assert_ne!(a.addr(), b.addr());
+
+        finish_tx.send(()).unwrap();
+        assert_eq!(reader.join().unwrap(), Some(1));
+
+        // トランザクションの終了後は再利用される
+        let c = stm.new_tvar(3u64);
+        assert_eq!(a.addr(), c.addr());
+    }
+
+    #[test]
+    fn memory_grows_into_new_segments() {
+        let stm: STM = STM::with_capacity(64);
+
+        // 最初のセグメントを超えて割り当て、以前のTVarが読み込めることを確認
+        let vars: Vec<_> = (0..100u64).map(|i| stm.new_tvar([i; 3])).collect();
+        assert!(vars.last().unwrap().addr() >= 64);
+        for (i, var) in vars.iter().enumerate() {
+            let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, *var)));
+            assert_eq!(val, Some([i as u64; 3]));
+        }
+
+        // 既存のセグメントより大きな領域は、収まるセグメントから割り当てる
+        let big = stm.write_transaction(|tr| STMResult::Ok(tr.alloc_array(1000, 7u64)));
+        let big = big.unwrap().unwrap();
+        let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, big.offset(999))));
+        assert_eq!(val, Some(7));
+        let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, vars[0])));
+        assert_eq!(val, Some([0; 3]));
+    }
+
+    #[test]
+    fn oversized_alloc_fails() {
+        let stm: STM = STM::with_capacity(64);
+
+        // 最大のセグメントにも収まらない領域や、サイズの計算がオーバーフローする領域はNone
+        let addr = stm.write_transaction(|tr| STMResult::Ok(tr.alloc(usize::MAX)));
+        assert_eq!(addr, Some(None));
+        let var = stm.write_transaction(|tr| STMResult::Ok(tr.alloc_array(usize::MAX / 2, 0u64)));
+        assert!(var.unwrap().is_none());
+        let var = stm.write_transaction(|tr| STMResult::Ok(tr.alloc_stripes::<u64>(usize::MAX)));
+        assert!(var.unwrap().is_none());
+
+        // 失敗後も割り当て可能
+        let a = stm.new_tvar(5u64);
+        let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, a)));
+        assert_eq!(val, Some(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "free of unallocated address")]
+    fn double_free_panics() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(1u64);
+        stm.write_transaction(|tr| {
+            tr.free_var(a);
+            STMResult::Ok(())
+        });
+
+        // コミット済みの解放を再度解放した場合は再実行せずにpanic
+        stm.write_transaction(|tr| {
+            tr.free_var(a);
+            STMResult::Ok(())
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "free of unallocated address")]
+    fn free_of_null_panics() {
+        let stm: STM = STM::new();
+        stm.write_transaction(|tr| {
+            tr.free_var(TVar::<u64>::null());
+            STMResult::Ok(())
+        });
+    }
+
+    // ストライプのサイズを変えても、複数のストライプにまたがる値を読み書きできることを確認
+    fn multi_stripe_values<const STRIPE_SIZE: usize>() {
+        let stm: STM<STRIPE_SIZE> = STM::new();
+        let val: [u64; 20] = std::array::from_fn(|i| i as u64);
+        let a = stm.new_tvar(val);
+        assert!(TVar::<[u64; 20], STRIPE_SIZE>::NUM_STRIPES > 1);
+
+        let arr = stm.write_transaction(|tr| STMResult::Ok(tr.alloc_array(3, [7u64; 5])));
+        let arr = arr.unwrap().unwrap();
+        stm.write_transaction(|tr| {
+            let mut v = load_var!(tr, a);
+            v[19] = 100;
+            store_var!(tr, a, v);
+            store_var!(tr, arr.offset(1), [1, 2, 3, 4, 5]);
+            STMResult::Ok(())
+        });
+
+        let result = stm.write_transaction(|tr| {
+            let v = load_var!(tr, a);
+            let x = load_var!(tr, arr.offset(0));
+            let y = load_var!(tr, arr.offset(1));
+            let z = load_var!(tr, arr.offset(2));
+            STMResult::Ok((v, x, y, z))
+        });
+        let mut expected = val;
+        expected[19] = 100;
+        assert_eq!(result, Some((expected, [7; 5], [1, 2, 3, 4, 5], [7; 5])));
+    }
+
+    #[test]
+    fn stripe_size_16() {
+        multi_stripe_values::<16>();
+    }
+
+    #[test]
+    fn stripe_size_64() {
+        multi_stripe_values::<64>();
+    }
+
+    #[test]
+    fn or_else_rolls_back_first_branch() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(0u64);
+        let b = stm.new_tvar(0u64);
+
+        // f1がretryした場合はf1の書き込みを取り消してf2を実行
+        let result = stm.write_transaction(|tr| {
+            tr.or_else(
+                |tr| {
+                    store_var!(tr, a, 1);
+                    STMResult::Retry
+                },
+                |tr| {
+                    store_var!(tr, b, 1);
+                    STMResult::Ok(load_var!(tr, a))
+                },
+            )
+        });
+        assert_eq!(result, Some(0));
+
+        // f1がAbortした場合もf1の書き込みを取り消す
+        let result = stm.write_transaction(|tr| {
+            let aborted = tr.or_else(
+                |tr| {
+                    store_var!(tr, a, 2);
+                    STMResult::<()>::Abort
+                },
+                |_| unreachable!(),
+            );
+            assert!(matches!(aborted, STMResult::Abort));
+            STMResult::Ok(load_var!(tr, a))
+        });
+        assert_eq!(result, Some(0));
+
+        let committed =
+            stm.write_transaction(|tr| STMResult::Ok((load_var!(tr, a), load_var!(tr, b))));
+        assert_eq!(committed, Some((0, 1)));
+    }
+
+    #[test]
+    fn nested_abort_rolls_back_only_inner_writes() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(1u64);
+        let b = stm.new_tvar(1u64);
+
+        let result = stm.write_transaction(|tr| {
+            store_var!(tr, a, 2);
+            let inner = tr.nested(|tr| {
+                store_var!(tr, a, 3);
+                store_var!(tr, b, 3);
+                STMResult::<()>::Abort
+            });
+            assert!(matches!(inner, STMResult::Abort));
+
+            // 親トランザクションの書き込みのみ残る
+            let va = load_var!(tr, a);
+            let vb = load_var!(tr, b);
+            STMResult::Ok((va, vb))
+        });
+        assert_eq!(result, Some((2, 1)));
+
+        let committed =
+            stm.write_transaction(|tr| STMResult::Ok((load_var!(tr, a), load_var!(tr, b))));
+        assert_eq!(committed, Some((2, 1)));
+    }
+
+    #[test]
+    fn hooks_run_on_commit_and_abort() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(0u64);
+        let commits = Rc::new(Cell::new(0));
+        let aborts = Rc::new(Cell::new(0));
+
+        stm.write_transaction(|tr| {
+            let c = commits.clone();
+            tr.on_commit(move || c.set(c.get() + 1));
+            let ab = aborts.clone();
+            tr.on_abort(move || ab.set(ab.get() + 1));
+            store_var!(tr, a, 1);
+            STMResult::Ok(())
+        });
+        assert_eq!((commits.get(), aborts.get()), (1, 0));
+
+        stm.write_transaction(|tr| {
+            let c = commits.clone();
+            tr.on_commit(move || c.set(c.get() + 1));
+            let ab = aborts.clone();
+            tr.on_abort(move || ab.set(ab.get() + 1));
+            STMResult::<()>::Abort
+        });
+        assert_eq!((commits.get(), aborts.get()), (1, 1));
+    }
+
+    // 競合する加算を並行に実行し、結果と統計情報の整合性を確認
+    // 統計情報の増分を返す
+    fn contended_increments(stm: STM) -> Stats {
+        const THREADS: u64 = 4;
+        const ITERS: u64 = 200;
+
+        let stm = Arc::new(stm);
+        let a = stm.new_tvar(0u64);
+        let runs = Arc::new(AtomicU64::new(0));
+        let before = stm.stats();
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let stm = stm.clone();
+                let runs = runs.clone();
+                thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        stm.write_transaction(|tr| {
+                            runs.fetch_add(1, Ordering::Relaxed);
+                            let v = load_var!(tr, a);
+                            // 読み込みと書き込みの間に他のスレッドを割り込ませる
+                            thread::sleep(Duration::from_micros(10));
+                            store_var!(tr, a, v + 1);
+                            STMResult::Ok(())
+                        });
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let after = stm.stats();
+        let delta = Stats {
+            commits: after.commits - before.commits,
+            validation_aborts: after.validation_aborts - before.validation_aborts,
+            lock_aborts: after.lock_aborts - before.lock_aborts,
+            retries: after.retries - before.retries,
+            exclusive_runs: after.exclusive_runs - before.exclusive_runs,
+        };
+
+        // 加算が失われず、実行回数はコミット数と中断数の和に一致
+        let val = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, a)));
+        assert_eq!(val, Some(THREADS * ITERS));
+        assert_eq!(delta.commits, THREADS * ITERS);
+        assert_eq!(delta.retries, 0);
+        assert_eq!(
+            runs.load(Ordering::Relaxed),
+            delta.commits + delta.validation_aborts + delta.lock_aborts
+        );
+        delta
+    }
+
+    #[test]
+    fn contention_managers_keep_counts_consistent() {
+        let stats = contended_increments(STM::with_contention_manager(Aggressive));
+        assert_eq!(stats.exclusive_runs, 0);
+        let stats = contended_increments(STM::with_contention_manager(Backoff::default()));
+        assert_eq!(stats.exclusive_runs, 0);
+        let stats = contended_increments(STM::with_contention_manager(Karma::default()));
+        assert_eq!(stats.exclusive_runs, 0);
+    }
+
+    #[test]
+    fn global_lock_fallback_reaches_exclusive_mode() {
+        // 一度中断したトランザクションは排他的に再実行され、必ずコミットする
+        let cm = GlobalLockFallback::new(1, Aggressive);
+        let stats = contended_increments(STM::with_contention_manager(cm));
+        assert!(stats.exclusive_runs > 0);
+        assert_eq!(
+            stats.exclusive_runs,
+            stats.validation_aborts + stats.lock_aborts
+        );
+    }
+
+    #[test]
+    fn global_lock_fallback_after_conflict() {
+        let stm: Arc<STM> = Arc::new(STM::with_contention_manager(GlobalLockFallback::new(
+            1, Aggressive,
+        )));
+        let a = stm.new_tvar(0u64);
+        let before = stm.stats();
+        let runs = Cell::new(0);
+
+        // 最初の実行中に他のスレッドが書き込み、read-setの検証を失敗させる
+        let val = stm.write_transaction(|tr| {
+            runs.set(runs.get() + 1);
+            let v = load_var!(tr, a);
+            if runs.get() == 1 {
+                let stm = stm.clone();
+                thread::spawn(move || {
+                    stm.write_transaction(|tr| {
+                        store_var!(tr, a, 10);
+                        STMResult::Ok(())
+                    })
+                })
+                .join()
+                .unwrap();
+            }
+            store_var!(tr, a, v + 1);
+            STMResult::Ok(v + 1)
+        });
+        assert_eq!((val, runs.get()), (Some(11), 2));
+
+        let after = stm.stats();
+        assert_eq!(after.commits - before.commits, 2);
+        assert_eq!(after.validation_aborts - before.validation_aborts, 1);
+        assert_eq!(after.exclusive_runs - before.exclusive_runs, 1);
+    }
+
+    #[test]
+    fn panic_in_exclusive_transaction_does_not_poison() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(0u64);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            stm.irrevocable_transaction(|tr| -> STMResult<()> {
+                store_var!(tr, a, 1);
+                panic!("panic in irrevocable transaction");
+            })
+        }));
+        assert!(result.is_err());
+
+        // panic後も排他的な実行と通常の実行が可能で、書き込みは破棄されている
+        let val = stm.irrevocable_transaction(|tr| STMResult::Ok(load_var!(tr, a)));
+        assert_eq!(val, Some(0));
+        let val = stm.write_transaction(|tr| {
+            let val = load_var!(tr, a);
+            store_var!(tr, a, val + 1);
+            STMResult::Ok(val + 1)
+        });
+        assert_eq!(val, Some(1));
+    }
+
+    #[test]
+    fn irrevocable_transaction_runs_once() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(0u64);
+        let runs = Cell::new(0);
+
+        let result = stm.irrevocable_transaction(|tr| {
+            runs.set(runs.get() + 1);
+            let v = load_var!(tr, a);
+            store_var!(tr, a, v + 1);
+            STMResult::Ok(v)
+        });
+        assert_eq!((result, runs.get()), (Some(0), 1));
+        let committed = stm.write_transaction(|tr| STMResult::Ok(load_var!(tr, a)));
+        assert_eq!(committed, Some(1));
+    }
+
+    #[test]
+    fn irrevocable_retry_returns_none() {
+        let stm: STM = STM::new();
+        let a = stm.new_tvar(0u64);
+        let runs = Cell::new(0);
+
+        // retryしても待機や再実行はせず、書き込みを破棄してNone
+        let result = stm.irrevocable_transaction(|tr| -> STMResult<()> {
+            runs.set(runs.get() + 1);
+            store_var!(tr, a, 1);
+            STMResult::Retry
+        });
+        assert_eq!((result, runs.get()), (None, 1));
+
+        // グローバルロックは解放されている
+        let val = stm.irrevocable_transaction(|tr| STMResult::Ok(load_var!(tr, a)));
+        assert_eq!(val, Some(0));
+    }
+}